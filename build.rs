@@ -0,0 +1,56 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Output of a command run at build time, if it could be run
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|output| !output.is_empty())
+}
+
+/// Build details for `nginx_exporter_build_info`. Builds outside a git checkout (e.g.
+/// from a source archive) report `unknown` as the commit.
+fn main() {
+    let commit = command_output("git", &["rev-parse", "--short=12", "HEAD"]);
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        commit.as_deref().unwrap_or("unknown")
+    );
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC_VERSION={}",
+        rustc_version
+            .as_deref()
+            .and_then(|version| version.split_whitespace().nth(1))
+            .unwrap_or("unknown")
+    );
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // A new commit changes HEAD or the branch it points to
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
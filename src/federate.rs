@@ -0,0 +1,364 @@
+//! Rollup of other exporters (`--federate-from`): the histogram and counter samples
+//! of their `/metrics` are summed with the local ones, series by series.
+
+use crate::metrics::{parse_series, split_sample};
+use crate::push::{send_request, split_http_url};
+use crate::state::MetricsState;
+use log::warn;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
+
+/// Client for the `/metrics` endpoint of one exporter to roll up.
+pub struct FederationSource {
+    pub url: String,
+    host: String,
+    path: String,
+}
+
+impl FederationSource {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let (host, path) =
+            split_http_url(url).map_err(|e| format!("Invalid --federate-from URL: {}", e))?;
+
+        // A bare host:port means the default metrics path
+        let path = match path.is_empty() {
+            true => "/metrics".to_string(),
+            false => path,
+        };
+
+        Ok(Self {
+            url: url.to_string(),
+            host,
+            path,
+        })
+    }
+
+    pub async fn fetch(&self) -> Result<String, String> {
+        let body = send_request(
+            &self.host,
+            "GET",
+            &self.path,
+            &[("Accept", "text/plain; version=0.0.4")],
+            &[],
+        )
+        .await?;
+
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+/// Result of the last scrape of a source. The text of the last successful one is kept
+/// while the source is unreachable, so its counters don't leave the sums and look reset.
+pub struct FederatedScrape {
+    pub source: String,
+    pub up: bool,
+    pub text: Option<String>,
+}
+
+impl FederatedScrape {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            up: false,
+            text: None,
+        }
+    }
+}
+
+/// Scrapes every source at once, giving up on those that didn't answer within
+/// `timeout`, and updates their `FederatedScrape`s in `state`. A hanging source only
+/// misses its own round, the others are updated meanwhile.
+pub async fn scrape_all(
+    sources: &[Arc<FederationSource>],
+    state: &Mutex<MetricsState>,
+    timeout: Duration,
+) {
+    let deadline = Instant::now() + timeout;
+    let mut scrapes = JoinSet::new();
+
+    for (i, source) in sources.iter().enumerate() {
+        let source = Arc::clone(source);
+        scrapes.spawn(async move {
+            let result = timeout_at(deadline, source.fetch())
+                .await
+                .unwrap_or_else(|_| Err("timed out".to_string()));
+
+            if let Err(e) = &result {
+                warn!("Failed to scrape {}: {}", source.url, e);
+            }
+
+            (i, result)
+        });
+    }
+
+    let mut results: Vec<_> = scrapes.join_all().await;
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut state = state.lock().unwrap();
+    for (scrape, (_, result)) in state.federated.iter_mut().zip(results) {
+        scrape.up = result.is_ok();
+
+        if let Ok(text) = result {
+            scrape.text = Some(text);
+        }
+    }
+}
+
+// The exporter's own metrics describe one instance and are not added up
+fn summable(name: &str, kind: &str) -> bool {
+    matches!(kind, "histogram" | "counter") && !name.starts_with("nginx_exporter_")
+}
+
+#[derive(Default)]
+struct Family {
+    name: String,
+    kind: String,
+    header: Vec<String>,
+    // Sample lines of a family that isn't summed, kept as they are
+    lines: Vec<String>,
+    // Summed values by series (`name{labels}`), in the order first seen
+    series: Vec<(String, f64)>,
+    index: HashMap<String, usize>,
+}
+
+impl Family {
+    fn add(&mut self, series: &str, value: f64) {
+        match self.index.get(series) {
+            Some(&i) => self.series[i].1 += value,
+            None => {
+                self.index.insert(series.to_string(), self.series.len());
+                self.series.push((series.to_string(), value));
+            }
+        }
+    }
+
+    // `le` values of the buckets of a histogram, across its series
+    fn bucket_bounds(&self) -> BTreeSet<String> {
+        self.series
+            .iter()
+            .flat_map(|(series, _)| parse_series(series).1)
+            .filter_map(|(name, value)| (name == "le").then_some(value))
+            .collect()
+    }
+
+    // Series can only be summed with the same type and, for histograms, the same buckets
+    fn compatible(&self, other: &Family) -> bool {
+        self.kind == other.kind
+            && (self.kind != "histogram" || self.bucket_bounds() == other.bucket_bounds())
+    }
+}
+
+// Families in the order of the text, a family starts with its first metadata line
+fn parse_families(text: &str) -> Vec<Family> {
+    let mut families: Vec<Family> = Vec::new();
+
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (keyword, name) = (parts.next(), parts.next().unwrap_or_default());
+
+            if families.last().is_none_or(|family| family.name != name) {
+                families.push(Family {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+            }
+
+            let family = families.last_mut().unwrap();
+            if keyword == Some("TYPE") {
+                family.kind = parts.next().unwrap_or_default().to_string();
+            }
+            family.header.push(line.to_string());
+            continue;
+        }
+
+        if families.is_empty() {
+            families.push(Family::default());
+        }
+
+        let family = families.last_mut().unwrap();
+        match split_sample(line).filter(|_| summable(&family.name, &family.kind)) {
+            Some((series, value)) => family.add(series, value),
+            None => family.lines.push(line.to_string()),
+        }
+    }
+
+    families
+}
+
+/// Adds the histogram and counter series of every scraped source to those of the local
+/// text exposition. Families only found in sources are appended, and a family whose
+/// type or histogram buckets differ from the local one is left out. Also returns the
+/// number of families left out for each scrape.
+pub fn merge_federated(local: &str, scrapes: &[FederatedScrape]) -> (String, Vec<usize>) {
+    let mut families = parse_families(local);
+    let mut skipped = Vec::with_capacity(scrapes.len());
+
+    for scrape in scrapes {
+        let mut families_skipped = 0;
+
+        for family in parse_families(scrape.text.as_deref().unwrap_or_default()) {
+            if !summable(&family.name, &family.kind) {
+                continue;
+            }
+
+            match families.iter_mut().find(|local| local.name == family.name) {
+                Some(local) if local.compatible(&family) => {
+                    for (series, value) in &family.series {
+                        local.add(series, *value);
+                    }
+                }
+                Some(_) => families_skipped += 1,
+                None => families.push(family),
+            }
+        }
+
+        skipped.push(families_skipped);
+    }
+
+    let text = families
+        .into_iter()
+        .flat_map(|family| {
+            let series = family
+                .series
+                .into_iter()
+                .map(|(series, value)| format!("{} {}", series, value));
+
+            family.header.into_iter().chain(family.lines).chain(series)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (text, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::render_text;
+    use crate::push::tests::{mock_server, ok};
+    use crate::state::tests::{log_line, record, settings};
+    use crate::state::MetricsState;
+
+    // Text exposition of an exporter that read `lines`, with the given duration buckets
+    fn exposition(buckets: &[f64], lines: &[String]) -> String {
+        let mut settings = settings("unused");
+        settings.buckets = buckets.to_vec();
+        let mut state = MetricsState::new(settings);
+        record(&mut state, lines);
+        let metrics = state.metrics.clone();
+        render_text(&state, &metrics, false, false)
+    }
+
+    fn sample(text: &str, series: &str) -> Option<f64> {
+        text.lines()
+            .filter_map(split_sample)
+            .find(|(name, _)| *name == series)
+            .map(|(_, value)| value)
+    }
+
+    #[tokio::test]
+    async fn buckets_of_two_exporters_are_summed() {
+        let buckets = [0.1, 1.0];
+        let mut scrapes = Vec::new();
+
+        for lines in [
+            vec![log_line("/a", "200", "0.05")],
+            vec![log_line("/a", "200", "0.5"), log_line("/a", "200", "5")],
+        ] {
+            let (address, _) = mock_server(ok(&exposition(&buckets, &lines))).await;
+            let source = FederationSource::new(&format!("http://{}", address)).unwrap();
+            let mut scrape = FederatedScrape::new(&source.url);
+            scrape.text = Some(source.fetch().await.unwrap());
+            scrape.up = true;
+            scrapes.push(scrape);
+        }
+
+        let mut settings = settings("unused");
+        settings.buckets = buckets.to_vec();
+        let mut state = MetricsState::new(settings);
+        record(&mut state, &[log_line("/a", "200", "0.05")]);
+        state.federated = scrapes;
+
+        let metrics = state.metrics.clone();
+        let text = render_text(&state, &metrics, false, false);
+
+        let bucket = |le: &str| {
+            sample(
+                &text,
+                &format!(
+                    "nginx_http_request_duration_seconds_bucket{{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\",le=\"{}\"}}",
+                    le
+                ),
+            )
+        };
+        assert_eq!(bucket("0.1"), Some(2.0));
+        assert_eq!(bucket("1"), Some(3.0));
+        assert_eq!(bucket("+Inf"), Some(4.0));
+        assert!(text.contains("nginx_exporter_federation_families_skipped{source=\""));
+        assert!(!text.lines().any(|line| line
+            .starts_with("nginx_exporter_federation_families_skipped{")
+            && !line.ends_with(" 0")));
+    }
+
+    #[test]
+    fn histograms_with_other_buckets_are_left_out() {
+        let lines = [log_line("/a", "200", "0.05")];
+        let local = exposition(&[0.1, 1.0], &lines);
+
+        let mut other = FederatedScrape::new("other");
+        other.text = Some(exposition(&[0.5], &lines));
+        let mut same = FederatedScrape::new("same");
+        same.text = Some(exposition(&[0.1, 1.0], &lines));
+
+        let (text, skipped) = merge_federated(&local, &[other, same]);
+
+        // Only the request duration buckets differ, request sizes are still summed
+        assert_eq!(skipped, [1, 0]);
+        let count = "nginx_http_request_duration_seconds_count{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\"}";
+        assert_eq!(sample(&text, count), Some(2.0));
+        assert!(!text.contains("le=\"0.5\""));
+    }
+
+    #[tokio::test]
+    async fn a_hanging_source_does_not_hold_up_the_others() {
+        // Accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hanging = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let text = exposition(&[0.5], &[log_line("/a", "200", "0.1")]);
+        let (healthy, _) = mock_server(ok(&text)).await;
+
+        let sources: Vec<_> = [hanging.to_string(), healthy.clone(), hanging.to_string()]
+            .iter()
+            .map(|address| Arc::new(FederationSource::new(&format!("http://{}", address)).unwrap()))
+            .collect();
+        let state = Mutex::new(MetricsState::new(settings("unused")));
+        state.lock().unwrap().federated = sources
+            .iter()
+            .map(|source| FederatedScrape::new(&source.url))
+            .collect();
+
+        let interval = Duration::from_millis(500);
+        let started = std::time::Instant::now();
+        scrape_all(&sources, &state, interval).await;
+
+        // Both hanging sources share the one deadline
+        assert!(started.elapsed() < interval * 2);
+
+        let state = state.lock().unwrap();
+        let up: Vec<_> = state.federated.iter().map(|scrape| scrape.up).collect();
+        assert_eq!(up, [false, true, false]);
+        assert_eq!(state.federated[1].text.as_deref(), Some(text.as_str()));
+        assert!(state.federated[0].text.is_none());
+    }
+}
@@ -0,0 +1,69 @@
+use log::{debug, error};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Reads a named pipe on a dedicated thread. A FIFO can't be seeked or reopened at
+/// a position like a file, so its lines are handed over as they arrive and kept in
+/// memory until the next read cycle takes them.
+pub struct FifoReader {
+    receiver: Receiver<Vec<u8>>,
+    // Start of an entry that wasn't complete at the end of the last cycle
+    pub pending: Vec<u8>,
+}
+
+impl FifoReader {
+    pub fn spawn(path: &Path, buffer_bytes: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_path_buf();
+
+        thread::spawn(move || read_lines(path, buffer_bytes, sender));
+
+        Self {
+            receiver,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Everything received since the last call, after what was left pending.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend(self.receiver.try_iter().flatten());
+        data
+    }
+}
+
+fn read_lines(path: PathBuf, buffer_bytes: usize, sender: Sender<Vec<u8>>) {
+    // Opened for writing as well, so the open doesn't wait for nginx to open the pipe
+    // and reads don't hit end of file whenever nginx closes it on reload
+    let file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open FIFO {}: {}", path.to_string_lossy(), e);
+            return;
+        }
+    };
+
+    let mut reader = BufReader::with_capacity(buffer_bytes, file);
+
+    loop {
+        let mut line = Vec::new();
+
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) => {
+                // The FIFO was dropped from watch, the line read meanwhile is lost
+                if sender.send(line).is_err() {
+                    debug!("Stop reading FIFO {}", path.to_string_lossy());
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read FIFO {}: {}", path.to_string_lossy(), e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Log files served over HTTP (`--http-source`), tailed with `Range` requests from the
+//! byte offset reached so far. Only plain `http://` and uncompressed logs: the client
+//! has no TLS, and a gzip stream can't be decoded from an offset.
+
+use crate::push::{request, split_http_url, HttpResponse};
+use crate::state::{MetricsState, TailEntry};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Instant};
+
+// Largest range requested at once. A response is only used once complete, so a backlog
+// is caught up chunk by chunk, keeping what arrived before a round timed out
+const CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Client for one log file, tracking how much of it was fetched.
+pub struct HttpSource {
+    pub url: String,
+    host: String,
+    path: String,
+    pub offset: u64,
+    chunk_bytes: u64,
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let (host, path) =
+            split_http_url(url).map_err(|e| format!("Invalid --http-source URL: {}", e))?;
+
+        Ok(Self {
+            url: url.to_string(),
+            host,
+            path,
+            offset: 0,
+            chunk_bytes: CHUNK_BYTES,
+        })
+    }
+
+    /// Bytes appended to the file since the last fetch, at most one chunk of them. A
+    /// file that got shorter was rotated and is read again from the start.
+    pub async fn fetch(&mut self) -> Result<Vec<u8>, String> {
+        let range = format!(
+            "bytes={}-{}",
+            self.offset,
+            self.offset + self.chunk_bytes - 1
+        );
+        let response = request(
+            &self.host,
+            "GET",
+            &self.path,
+            &[("Range", &range), ("Accept-Encoding", "identity")],
+            &[],
+        )
+        .await?;
+
+        match response.status {
+            206 => {
+                let start = content_range(&response).map(|(start, _)| start);
+                if start != Some(self.offset) {
+                    return Err(format!(
+                        "{} answered range {} with Content-Range {:?}",
+                        self.url,
+                        range,
+                        response.header("content-range")
+                    ));
+                }
+
+                self.offset += response.body.len() as u64;
+                Ok(response.body)
+            }
+            // The server ignored the range and sent the whole file
+            200 => {
+                let mut body = response.body;
+                if (body.len() as u64) < self.offset {
+                    debug!("{} got shorter, reading it from the start", self.url);
+                    self.offset = 0;
+                }
+
+                let new = body.split_off(self.offset as usize);
+                self.offset += new.len() as u64;
+                Ok(new)
+            }
+            // Nothing past the offset, unless the file got shorter
+            416 => {
+                if content_range(&response).is_some_and(|(_, len)| len < self.offset) {
+                    debug!("{} got shorter, reading it from the start", self.url);
+                    self.offset = 0;
+                }
+
+                Ok(Vec::new())
+            }
+            status => Err(format!("{} answered with status {}", self.url, status)),
+        }
+    }
+}
+
+/// Fetches every source at once until `timeout`, appending what arrived to their tails
+/// in `state`. A source is read chunk by chunk until it is caught up, so one that stalls
+/// or has a large backlog doesn't hold up the others, and keeps the chunks it received.
+pub async fn fetch_all(
+    sources: &mut Vec<HttpSource>,
+    state: &Mutex<MetricsState>,
+    timeout: Duration,
+) {
+    let deadline = Instant::now() + timeout;
+    let mut fetches = JoinSet::new();
+
+    for (i, mut source) in std::mem::take(sources).into_iter().enumerate() {
+        fetches.spawn(async move {
+            let mut data = Vec::new();
+
+            loop {
+                match timeout_at(deadline, source.fetch()).await {
+                    Ok(Ok(chunk)) => {
+                        let caught_up = (chunk.len() as u64) < source.chunk_bytes;
+                        data.extend(chunk);
+
+                        if caught_up {
+                            break;
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Failed to fetch {}: {}", source.url, e);
+                        break;
+                    }
+                    Err(_) => {
+                        warn!("Failed to fetch {}: timed out", source.url);
+                        break;
+                    }
+                }
+            }
+
+            (i, source, data)
+        });
+    }
+
+    let mut results = fetches.join_all().await;
+    results.sort_by_key(|(i, _, _)| *i);
+
+    // Parsed by the next read cycle, like the lines of a FIFO
+    let mut state = state.lock().unwrap();
+    for (i, source, data) in results {
+        if let Some(tail) = state.http_sources.get_mut(i) {
+            tail.data.extend(data);
+        }
+        sources.push(source);
+    }
+}
+
+// Start and complete length of `Content-Range: bytes <start>-<end>/<len>` (or `*/<len>`)
+fn content_range(response: &HttpResponse) -> Option<(u64, u64)> {
+    let value = response.header("content-range")?.strip_prefix("bytes ")?;
+    let (range, len) = value.split_once('/')?;
+    let start = match range {
+        "*" => 0,
+        range => range.split_once('-')?.0.parse().ok()?,
+    };
+
+    Some((start, len.parse().ok()?))
+}
+
+/// Data fetched from one source that the read cycle hasn't parsed yet, filled by the
+/// fetch task and drained line by line like a FIFO.
+pub struct HttpTail {
+    pub url: String,
+    pub data: Vec<u8>,
+    // Most recent parsed entries, bounded by --tail-buffer
+    pub recent: VecDeque<TailEntry>,
+}
+
+impl HttpTail {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            data: Vec::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// The complete lines received so far, a line still being written stays behind.
+    pub fn take_lines(&mut self) -> Vec<String> {
+        let Some(end) = self.data.iter().rposition(|&byte| byte == b'\n') else {
+            return Vec::new();
+        };
+
+        let rest = self.data.split_off(end + 1);
+        let data = std::mem::replace(&mut self.data, rest);
+
+        String::from_utf8_lossy(&data)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::tests::{mock_server, MockRequest};
+    use crate::state::tests::{log_line, settings};
+    use crate::state::MetricsState;
+    use std::sync::{Arc, Mutex};
+
+    // Serves `content` honouring `Range: bytes=<start>-<end>`, like nginx serving a
+    // static file
+    fn serve_ranges(
+        content: Arc<Mutex<Vec<u8>>>,
+    ) -> impl Fn(&MockRequest) -> (u16, Vec<(String, String)>, Vec<u8>) + Send + Sync + 'static
+    {
+        move |request| {
+            let content = content.lock().unwrap().clone();
+            let (start, end) = request
+                .header("range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(start, end)| (start.parse().unwrap(), end.parse::<usize>().ok()))
+                .unwrap_or((0, None));
+
+            if start >= content.len() {
+                let range = format!("bytes */{}", content.len());
+                return (416, vec![("Content-Range".to_string(), range)], Vec::new());
+            }
+
+            let end = end.map_or(content.len(), |end| (end + 1).min(content.len()));
+            let range = format!("bytes {}-{}/{}", start, end - 1, content.len());
+            (
+                206,
+                vec![("Content-Range".to_string(), range)],
+                content[start..end].to_vec(),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_only_what_was_appended() {
+        let content = Arc::new(Mutex::new(b"one\ntw".to_vec()));
+        let (address, requests) = mock_server(serve_ranges(Arc::clone(&content))).await;
+        let mut source = HttpSource::new(&format!("http://{}/access.log", address)).unwrap();
+        let mut tail = HttpTail::new(&source.url);
+
+        tail.data.extend(source.fetch().await.unwrap());
+        assert_eq!(tail.take_lines(), ["one"]);
+
+        content.lock().unwrap().extend(b"o\nthree\n");
+        tail.data.extend(source.fetch().await.unwrap());
+        assert_eq!(tail.take_lines(), ["two", "three"]);
+        assert_eq!(source.offset, 14);
+
+        // Nothing new: 416, no data
+        assert!(source.fetch().await.unwrap().is_empty());
+
+        // Rotated: shorter than the offset, read again from the start
+        *content.lock().unwrap() = b"new\n".to_vec();
+        assert!(source.fetch().await.unwrap().is_empty());
+        assert_eq!(source.fetch().await.unwrap(), b"new\n");
+
+        let requests = requests.lock().unwrap();
+        let ranges: Vec<_> = requests
+            .iter()
+            .map(|request| request.header("range").unwrap().to_string())
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                "bytes=0-4194303",
+                "bytes=6-4194309",
+                "bytes=14-4194317",
+                "bytes=14-4194317",
+                "bytes=0-4194303"
+            ]
+        );
+        assert_eq!(requests[0].path, "/access.log");
+    }
+
+    #[test]
+    fn read_cycle_parses_complete_lines_only() {
+        let mut tail = HttpTail::new("http://agent/access.log");
+        tail.data = format!("{}\n{{\"partial", log_line("/a", "200", "0.1")).into_bytes();
+
+        let mut state = MetricsState::new(settings("unused"));
+        state.http_sources = vec![tail];
+        state.read_new_entries().unwrap();
+
+        assert_eq!(state.entries_parsed_total, 1);
+        assert_eq!(state.http_sources[0].data, b"{\"partial");
+    }
+
+    #[tokio::test]
+    async fn a_backlog_is_fetched_in_chunks_next_to_a_stalled_source() {
+        // Accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let content = Arc::new(Mutex::new(b"one\ntwo\nthree\n".to_vec()));
+        let (address, requests) = mock_server(serve_ranges(Arc::clone(&content))).await;
+
+        let mut sources: Vec<_> = [stalled.to_string(), address]
+            .iter()
+            .map(|address| {
+                let mut source =
+                    HttpSource::new(&format!("http://{}/access.log", address)).unwrap();
+                source.chunk_bytes = 4;
+                source
+            })
+            .collect();
+        let state = Mutex::new(MetricsState::new(settings("unused")));
+        state.lock().unwrap().http_sources = sources
+            .iter()
+            .map(|source| HttpTail::new(&source.url))
+            .collect();
+
+        let timeout = Duration::from_millis(500);
+        let started = std::time::Instant::now();
+        fetch_all(&mut sources, &state, timeout).await;
+        assert!(started.elapsed() < timeout * 2);
+
+        // The stalled source got nothing, the other was caught up 4 bytes at a time
+        let mut state = state.lock().unwrap();
+        assert_eq!(sources[0].offset, 0);
+        assert_eq!(sources[1].offset, 14);
+        assert!(state.http_sources[0].data.is_empty());
+        assert_eq!(state.http_sources[1].take_lines(), ["one", "two", "three"]);
+
+        let requests = requests.lock().unwrap();
+        let ranges: Vec<_> = requests
+            .iter()
+            .map(|request| request.header("range").unwrap().to_string())
+            .collect();
+        assert_eq!(
+            ranges,
+            ["bytes=0-3", "bytes=4-7", "bytes=8-11", "bytes=12-15"]
+        );
+    }
+}
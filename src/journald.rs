@@ -0,0 +1,197 @@
+use crate::state::TailEntry;
+use log::{debug, error, warn};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+// Pause before following the journal again after journalctl exited
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads access-log records of one systemd unit from the journal. `journalctl --follow`
+/// runs on a dedicated thread, like a FIFO, so a read cycle only takes the messages
+/// received meanwhile and never waits for the journal. When journalctl exits it is
+/// started again after the cursor of the last record seen, the journal counterpart of
+/// `file_position`.
+pub struct JournalReader {
+    pub unit: String,
+    receiver: Receiver<String>,
+    // Most recent parsed entries, bounded by --tail-buffer
+    pub recent: VecDeque<TailEntry>,
+}
+
+impl JournalReader {
+    /// Follows `unit`, starting with its last `initial_lines` records (0 to only read
+    /// new ones).
+    pub fn spawn(unit: String, initial_lines: usize) -> Self {
+        Self::spawn_program("journalctl".to_string(), unit, initial_lines)
+    }
+
+    fn spawn_program(program: String, unit: String, initial_lines: usize) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let thread_unit = unit.clone();
+
+        thread::spawn(move || follow(program, thread_unit, initial_lines, sender));
+
+        Self {
+            unit,
+            receiver,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// The MESSAGE field of every record received since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+fn follow(program: String, unit: String, initial_lines: usize, sender: Sender<String>) {
+    let mut cursor: Option<String> = None;
+
+    loop {
+        let mut command = Command::new(&program);
+        command
+            .args([
+                "--unit",
+                &unit,
+                "--output",
+                "json",
+                "--no-pager",
+                "--follow",
+            ])
+            .args(["--output-fields", "MESSAGE"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+
+        match &cursor {
+            Some(cursor) => command.arg(format!("--after-cursor={}", cursor)),
+            None => command.arg(format!("--lines={}", initial_lines)),
+        };
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to run {}: {}", program, e);
+                return;
+            }
+        };
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            let Ok(record) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+
+            if let Some(record_cursor) = record["__CURSOR"].as_str() {
+                cursor = Some(record_cursor.to_string());
+            }
+
+            // Non UTF-8 messages are exported as byte arrays, they can't be JSON logs
+            let Some(message) = record["MESSAGE"].as_str() else {
+                debug!("Journal record without text MESSAGE. Skipped");
+                continue;
+            };
+
+            // The reader was dropped, nobody takes the messages anymore
+            if sender.send(message.to_string()).is_err() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+        }
+
+        match child.wait() {
+            Ok(status) => warn!("{} for unit {} exited with {}", program, unit, status),
+            Err(e) => warn!("{} for unit {} failed: {}", program, unit, e),
+        }
+
+        thread::sleep(RESTART_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{log_line, settings, temp_dir};
+    use crate::state::MetricsState;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Instant;
+
+    // Stands in for journalctl: logs its arguments, then prints two records on its
+    // first run and none afterwards
+    fn fake_journalctl(dir: &std::path::Path) -> String {
+        let records = [log_line("/a", "200", "0.1"), log_line("/b", "404", "0.2")]
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::json!({ "__CURSOR": format!("c{}", i + 1), "MESSAGE": line })
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let script = dir.join("journalctl");
+        let arguments = dir.join("arguments");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {args}\n[ -e {done} ] && exit 0\ntouch {done}\ncat <<'EOF'\n{records}\nEOF\n",
+                args = arguments.display(),
+                done = dir.join("done").display(),
+                records = records,
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        script.to_string_lossy().to_string()
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn journal_records_are_read_and_resumed_after_the_cursor() {
+        let dir = temp_dir("journald");
+        let program = fake_journalctl(&dir);
+
+        let mut state = MetricsState::new(settings("unused"));
+        state.journal = Some(JournalReader::spawn_program(
+            program,
+            "nginx.service".to_string(),
+            100,
+        ));
+
+        wait_for(|| {
+            state.read_new_entries().unwrap();
+            state.entries_parsed_total == 2
+        });
+        assert_eq!(state.current_requests().values().sum::<u64>(), 2);
+
+        // The restart after the first run resumes after the last record
+        let arguments = dir.join("arguments");
+        wait_for(|| {
+            std::fs::read_to_string(&arguments).is_ok_and(|arguments| arguments.lines().count() > 1)
+        });
+        let arguments = std::fs::read_to_string(&arguments).unwrap();
+        let mut runs = arguments.lines();
+        let first = runs.next().unwrap();
+        assert!(first.contains("--unit nginx.service"));
+        assert!(first.contains("--follow"));
+        assert!(first.contains("--lines=100"));
+        assert!(runs.next().unwrap().contains("--after-cursor=c2"));
+    }
+}
@@ -0,0 +1,19 @@
+//! Parsing, state and serving of the exporter, as a library so benchmarks and tests
+//! can use the modules directly. The binary in `main.rs` only wires them to the CLI.
+
+pub mod federate;
+pub mod fifo;
+pub mod http_source;
+#[cfg(feature = "journald")]
+pub mod journald;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod parser;
+pub mod protobuf;
+pub mod push;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+pub mod server;
+pub mod state;
+pub mod validate;
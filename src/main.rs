@@ -1,15 +1,23 @@
-use axum::middleware;
-use axum::{http::HeaderValue, http::StatusCode, response::Response, routing::get, Router};
 use clap::Parser;
-use glob::glob;
-use log::{debug, error, info, warn};
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use log::{error, info, warn};
+use nginx_prometheus_exporter::federate::{self, FederatedScrape, FederationSource};
+use nginx_prometheus_exporter::http_source::{self, HttpSource, HttpTail};
+use nginx_prometheus_exporter::metrics::{
+    exponential_buckets, render_text, validate_static_labels, LabelSanitize, MetricDescriptions,
+};
+#[cfg(feature = "otlp")]
+use nginx_prometheus_exporter::otlp;
+use nginx_prometheus_exporter::push::Pushgateway;
+#[cfg(feature = "remote-write")]
+use nginx_prometheus_exporter::remote_write;
+use nginx_prometheus_exporter::state::{MetricsState, Settings, StartupSettings};
+use nginx_prometheus_exporter::{server, validate};
+use regex::Regex;
+use std::future::IntoFuture;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
 
 #[derive(Parser, Debug)]
 #[command(author, version = env!("CARGO_PKG_VERSION"), about = "Nginx Prometheus Exporter by Frontend Infra Team", long_about = None)]
@@ -17,343 +25,1018 @@ struct Args {
     #[arg(short, long, default_value = "/var/log/nginx/*.log")]
     log_path: String,
 
+    /// Port to listen on
     #[arg(short, long, default_value = "9113")]
     port: u16,
-}
 
-#[derive(Debug, Deserialize)]
-struct NginxLogEntry {
-    http: HttpData,
-    nginx: NginxData,
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    bind_address: IpAddr,
+
+    /// Address and port to listen on (repeatable, e.g. 0.0.0.0:9113 and [::]:9114), instead of --bind-address and --port
+    #[arg(long, conflicts_with_all = ["port", "bind_address"])]
+    listen: Vec<SocketAddr>,
+
+    /// Attach trace IDs as exemplars to histogram buckets (OpenMetrics scrapes only)
+    #[arg(long)]
+    with_exemplars: bool,
+
+    /// Parse log entries as a JSON stream, allowing objects to span multiple lines
+    #[arg(long)]
+    json_multiline: bool,
+
+    /// Repair lines written without escape=json (\xHH escapes) or with invalid UTF-8 instead of rejecting them
+    #[arg(long)]
+    lenient_json: bool,
+
+    /// Keep only a running sum and count per series, exposing _sum and _count without buckets
+    #[arg(long)]
+    sum_count_only: bool,
+
+    /// Reset all series at the end of every window (e.g. 60s), exposing deltas instead of cumulative values
+    #[arg(long, value_parser = parse_duration)]
+    reset_interval: Option<Duration>,
+
+    /// Answer /metrics with 503 for this long after startup (e.g. 15s) and until a read cycle completed
+    #[arg(long, value_parser = parse_duration)]
+    warmup: Option<Duration>,
+
+    /// Maximum number of files to watch, further matches are skipped
+    #[arg(long)]
+    max_files: Option<usize>,
+
+    /// Add a protocol label (HTTP/1.1, HTTP/2.0, ...) from $server_protocol
+    #[arg(long)]
+    with_protocol: bool,
+
+    /// Add a scheme label (http, https) from $scheme, or inferred from $server_port when missing
+    #[arg(long)]
+    with_scheme: bool,
+
+    /// Add a cache_control label with the first directive of $sent_http_cache_control
+    #[arg(long)]
+    with_cache_control: bool,
+
+    /// Add an hour label (0-23, UTC) from $msec, to see daily traffic patterns
+    #[arg(long)]
+    with_hour_label: bool,
+
+    /// Count upstream responses by the status class of $upstream_status and the final status
+    #[arg(long)]
+    with_upstream_status: bool,
+
+    /// Append the $msec of the latest entry of each series to its /metrics samples, for backfills
+    #[arg(long)]
+    sample_timestamps: bool,
+
+    /// Count nginx's 499 (client closed request) as status_code="499" instead of 4xx
+    #[arg(long = "separate-499")]
+    separate_499: bool,
+
+    /// Expose nginx_worker_info{pid} for every worker pid found in nginx.pid ($pid)
+    #[arg(long)]
+    with_pid_info: bool,
+
+    /// Truncate the path label to its first N segments, e.g. 2 turns /api/v1/users/123 into /api/v1
+    #[arg(long)]
+    path_depth: Option<usize>,
+
+    /// Keep the query string of these paths (comma separated, e.g. /search,/api/report) as a query label; query strings of other paths are dropped
+    #[arg(long, value_delimiter = ',')]
+    query_label_paths: Vec<String>,
+
+    /// Start reading files found at startup from their end, only counting new lines
+    #[arg(long)]
+    start_at_end: bool,
+
+    /// Start reading files found at startup this many bytes before their end (e.g. 50MB), at the next line
+    #[arg(long, value_parser = parse_size, conflicts_with = "start_at_end")]
+    tail_bytes: Option<u64>,
+
+    /// Keep the last N parsed entries per file and serve them at /tail to requests with the --debug-token (0 disables the endpoint)
+    #[arg(long, default_value = "0")]
+    tail_buffer: usize,
+
+    /// Don't add the X-Powered-By header to responses
+    #[arg(long)]
+    no_powered_by: bool,
+
+    /// Maximum /metrics requests per second, further requests are answered with 429
+    #[arg(long, value_parser = parse_rate)]
+    scrape_rate_limit: Option<f64>,
+
+    /// Fields of http.response tried in order for the status code, the first present is used
+    #[arg(long, value_delimiter = ',', default_value = "status_code")]
+    status_fields: Vec<String>,
+
+    /// Path the metrics are served at
+    #[arg(long, default_value = "/metrics", value_parser = parse_metrics_path)]
+    metrics_path: String,
+
+    /// Serve /debug endpoints to requests with this bearer token (disabled when not set)
+    #[arg(long)]
+    debug_token: Option<String>,
+
+    /// How long a watched file may be missing (e.g. mid-rotation) before it is dropped from watch
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    missing_file_grace: Duration,
+
+    /// Read at most this many bytes per file and read cycle, spreading a large backlog over several cycles
+    #[arg(long)]
+    max_catchup_bytes: Option<u64>,
+
+    /// Replace hosts matching a pattern with a group name, as PATTERN=>GROUP where * matches anything (repeatable, first match wins)
+    #[arg(long, value_parser = parse_host_group)]
+    host_group: Vec<(Regex, String)>,
+
+    /// Label values with quotes, backslashes or control characters: escape them, or replace them with _
+    #[arg(long, default_value = "escape", value_parser = parse_label_sanitize)]
+    label_sanitize: LabelSanitize,
+
+    /// Skip raw log lines matching this regex before parsing, e.g. health checks
+    #[arg(long)]
+    line_exclude_regex: Option<Regex>,
+
+    /// Only parse raw log lines matching this regex
+    #[arg(long)]
+    line_include_regex: Option<Regex>,
+
+    /// Read buffer size per log file read, larger buffers mean fewer syscalls when catching up on a backlog
+    #[arg(long, default_value = "65536")]
+    read_buffer_bytes: usize,
+
+    /// Bucket upper bounds for one histogram, as NAME=B1,B2,... (repeatable)
+    #[arg(long, value_parser = parse_buckets_for)]
+    buckets_for: Vec<(String, Vec<f64>)>,
+
+    /// Default duration bucket bounds, read from a file with one bound per line or a JSON array
+    #[arg(long)]
+    buckets_file: Option<String>,
+
+    /// Maximum number of buckets a histogram may be configured with
+    #[arg(long, default_value = "64")]
+    max_buckets: usize,
+
+    /// Leave a metric family out of every output, e.g. nginx_http_request_size_bytes (repeatable)
+    #[arg(long)]
+    disable_metric: Vec<String>,
+
+    /// Add a label to every series, as NAME=VALUE (repeatable)
+    #[arg(long, value_parser = parse_key_value)]
+    static_label: Vec<(String, String)>,
+
+    /// Override the HELP text of a metric, as NAME=TEXT (repeatable)
+    #[arg(long, value_parser = parse_key_value)]
+    metric_help: Vec<(String, String)>,
+
+    /// Declare the unit of a metric for OpenMetrics, as NAME=UNIT (repeatable)
+    #[arg(long, value_parser = parse_key_value)]
+    metric_unit: Vec<(String, String)>,
+
+    /// Also read access-log records of this systemd unit from the journal (e.g. nginx.service)
+    #[cfg(feature = "journald")]
+    #[arg(long)]
+    journald_unit: Option<String>,
+
+    /// Records of the --journald-unit to read back from the journal at startup (none with --start-at-end)
+    #[cfg(feature = "journald")]
+    #[arg(long, default_value = "10000")]
+    journald_lines: usize,
+
+    /// Push metrics to this Pushgateway (e.g. http://pushgateway:9091) on every --push-interval
+    #[arg(long)]
+    pushgateway_url: Option<String>,
+
+    /// Interval between pushes to the Pushgateway or OTLP endpoint
+    #[arg(long, default_value = "15s", value_parser = parse_duration)]
+    push_interval: Duration,
+
+    /// Value of the job grouping key for pushed metrics
+    #[arg(long, default_value = "nginx-exporter")]
+    push_job: String,
+
+    /// Value of the instance grouping key for pushed metrics, omitted when not set
+    #[arg(long)]
+    push_instance: Option<String>,
+
+    /// Scrape these exporters' /metrics (comma separated URLs, e.g. http://web1:9113) and add their histograms and counters to the local ones
+    #[arg(long, value_delimiter = ',')]
+    federate_from: Vec<String>,
+
+    /// Interval between scrapes of the --federate-from exporters, also the timeout of each round of scrapes
+    #[arg(long, default_value = "15s", value_parser = parse_duration)]
+    federate_interval: Duration,
+
+    /// Also tail these log files served over plain HTTP with Range requests (comma separated URLs, e.g. http://agent1/access.log)
+    #[arg(long, value_delimiter = ',')]
+    http_source: Vec<String>,
+
+    /// Interval between fetches of the --http-source files, also the timeout of each round of fetches
+    #[arg(long, default_value = "15s", value_parser = parse_duration)]
+    http_source_interval: Duration,
+
+    /// Maximum time to wait for in-flight requests on shutdown before closing their connections
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    shutdown_timeout: Duration,
+
+    /// Export metrics to this OTLP/HTTP endpoint (e.g. http://otel-collector:4318) on every --push-interval
+    #[cfg(feature = "otlp")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Send metrics to this Prometheus remote write URL (e.g. http://prometheus:9090/api/v1/write) on every --push-interval
+    #[cfg(feature = "remote-write")]
+    #[arg(long)]
+    remote_write_url: Option<String>,
+
+    /// Parse the first --validate-lines lines of this sample log, report field coverage and exit
+    #[arg(long)]
+    validate: Option<String>,
+
+    /// Number of lines read from the --validate sample
+    #[arg(long, default_value = "100")]
+    validate_lines: usize,
 }
 
-#[derive(Debug, Deserialize)]
-struct HttpData {
-    response: ResponseData,
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE, got '{}'", value))?;
+
+    Ok((key.trim().to_string(), value.trim().to_string()))
 }
 
-#[derive(Debug, Deserialize)]
-struct ResponseData {
-    status_code: String,
+fn parse_buckets_for(value: &str) -> Result<(String, Vec<f64>), String> {
+    let (name, bounds) = parse_key_value(value)?;
+
+    let buckets = bounds
+        .split(',')
+        .map(|bound| {
+            bound
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid bucket bound '{}': {}", bound, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    validate_buckets(&name, &buckets)?;
+
+    Ok((name, buckets))
 }
 
-#[derive(Debug, Deserialize)]
-struct NginxData {
-    access: AccessData,
-    time: TimeData,
+fn validate_buckets(name: &str, buckets: &[f64]) -> Result<(), String> {
+    if buckets.iter().any(|bound| !bound.is_finite()) {
+        return Err(format!("Bucket bounds of {} must be finite", name));
+    }
+
+    if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(format!(
+            "Bucket bounds of {} must be strictly increasing",
+            name
+        ));
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct AccessData {
-    method: String,
-    url: String,
-    host: String,
+// Every bucket is a series per label set, so a typo here multiplies the exposition
+fn check_bucket_counts<'a>(
+    configured: impl IntoIterator<Item = (&'a str, &'a [f64])>,
+    max_buckets: usize,
+) -> Result<(), String> {
+    for (name, buckets) in configured {
+        if buckets.len() > max_buckets {
+            return Err(format!(
+                "{} is configured with {} buckets, more than --max-buckets {}",
+                name,
+                buckets.len(),
+                max_buckets
+            ));
+        }
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct TimeData {
-    request: String,
+// One bound per line (blank lines and # comments ignored), or a JSON array
+fn load_buckets_file(path: &str) -> Result<Vec<f64>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let buckets = if content.trim_start().starts_with('[') {
+        serde_json::from_str::<Vec<f64>>(&content)
+            .map_err(|e| format!("Invalid bucket array in {}: {}", path, e))?
+    } else {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse::<f64>()
+                    .map_err(|e| format!("Invalid bucket bound '{}' in {}: {}", line, path, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    if buckets.is_empty() {
+        return Err(format!("No bucket bounds in {}", path));
+    }
+
+    validate_buckets(path, &buckets)?;
+
+    Ok(buckets)
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-struct MetricLabels {
-    method: String,
-    path: String,
-    status_code: String,
-    host: String,
+fn parse_metrics_path(value: &str) -> Result<String, String> {
+    if !value.starts_with('/') {
+        return Err(format!("Path must start with /, got '{}'", value));
+    }
+
+    // Would be taken as route parameters
+    if value.contains([':', '*']) {
+        return Err(format!("Path can't contain ':' or '*', got '{}'", value));
+    }
+
+    // Served by the landing page and the other endpoints
+    if [
+        "/",
+        "/metrics.json",
+        "/tail",
+        "/config",
+        "/debug/last-error",
+        "/debug/cardinality",
+    ]
+    .contains(&value)
+    {
+        return Err(format!("Metrics can't be served at {}", value));
+    }
+
+    Ok(value.to_string())
 }
 
-fn get_status_label(status_code: String) -> Result<&'static str, String> {
-    let status = status_code
-        .parse::<u16>()
-        .map_err(|e| format!("Failed to parse status_code. Error: {}", e))?;
+fn parse_host_group(value: &str) -> Result<(Regex, String), String> {
+    let (pattern, group) = value
+        .split_once("=>")
+        .ok_or_else(|| format!("Expected PATTERN=>GROUP, got '{}'", value))?;
+
+    let (pattern, group) = (pattern.trim(), group.trim());
 
-    match status {
-        100..=199 => Ok("1xx"),
-        200..=299 => Ok("2xx"),
-        300..=399 => Ok("3xx"),
-        400..=499 => Ok("4xx"),
-        500..=599 => Ok("5xx"),
-        _ => Err("Unknown status code".to_string()),
+    if pattern.is_empty() || group.is_empty() {
+        return Err(format!("Expected PATTERN=>GROUP, got '{}'", value));
     }
+
+    // Only * is special, everything else matches literally
+    let regex = pattern
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+
+    let regex = Regex::new(&format!("^{}$", regex))
+        .map_err(|e| format!("Invalid host pattern '{}': {}", pattern, e))?;
+
+    Ok((regex, group.to_string()))
 }
 
-struct LogFileMeta {
-    file_position: u64,
-    inode: u64,
+fn parse_label_sanitize(value: &str) -> Result<LabelSanitize, String> {
+    match value {
+        "escape" => Ok(LabelSanitize::Escape),
+        "replace" => Ok(LabelSanitize::Replace),
+        _ => Err(format!("Expected escape or replace, got '{}'", value)),
+    }
 }
 
-struct MetricsState {
-    log_files: HashMap<PathBuf, LogFileMeta>,
-    metrics: HashMap<MetricLabels, Vec<f64>>,
-    pattern: String,
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid size '{}': {}", value, e))?;
+
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        unit => return Err(format!("Unknown size unit '{}' in '{}'", unit, value)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size '{}' is too large", value))
 }
 
-impl MetricsState {
-    fn new(pattern: String) -> Self {
-        Self {
-            log_files: HashMap::new(),
-            metrics: HashMap::new(),
-            pattern,
-        }
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(_) => Err(format!("Rate must be positive, got '{}'", value)),
+        Err(e) => Err(format!("Invalid rate '{}': {}", value, e)),
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number = number
+        .parse::<f64>()
+        .map_err(|e| format!("Invalid duration '{}': {}", value, e))?;
+
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("Unknown duration unit '{}' in '{}'", unit, value)),
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("Invalid duration '{}': {}", value, e))
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init_from_env(env_logger::Env::default().filter_or("LOG_LEVEL", "info"));
+
+    let args = Args::parse();
+
+    info!("Starting Nginx Prometheus Exporter");
+    info!("Log file: {:?}", args.log_path);
+
+    let descriptions = MetricDescriptions {
+        help: args.metric_help.into_iter().collect(),
+        units: args.metric_unit.into_iter().collect(),
+    };
+
+    if let Err(e) = descriptions.validate() {
+        error!("{}", e);
+        std::process::exit(1);
     }
 
-    fn update_files_map(&mut self) {
-        let entities = glob(&self.pattern)
-            .expect("Failed to read glob pattern")
-            .filter_map(Result::ok)
-            .collect::<Vec<_>>();
+    if let Err(e) = validate_static_labels(&args.static_label) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
-        let current_wath_file_pathes: Vec<_> = self.log_files.keys().cloned().collect();
+    // /tail is served behind the same token as the /debug endpoints
+    if args.tail_buffer > 0 && args.debug_token.is_none() {
+        error!("--tail-buffer requires --debug-token");
+        std::process::exit(1);
+    }
 
-        for path in current_wath_file_pathes {
-            if !entities.contains(&path) {
-                debug!("Remove file {} from watch", path.to_string_lossy());
-                self.log_files.remove(&path);
+    let buckets = match &args.buckets_file {
+        Some(path) => match load_buckets_file(path) {
+            Ok(buckets) => buckets,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
-        }
+        },
+        None => exponential_buckets(0.005, 2.0, 10),
+    };
 
-        for entry in glob(&self.pattern).expect("Failed to read glob pattern") {
-            match entry {
-                Ok(path) => {
-                    if let Some(_) = self.log_files.get(&path) {
-                        continue;
-                    }
+    let buckets_source = match args.buckets_file {
+        Some(_) => "--buckets-file",
+        None => "default duration buckets",
+    };
+
+    let configured = std::iter::once((buckets_source, buckets.as_slice())).chain(
+        args.buckets_for
+            .iter()
+            .map(|(name, buckets)| (name.as_str(), buckets.as_slice())),
+    );
 
-                    let inode = std::fs::metadata(&path).unwrap().ino();
+    if let Err(e) = check_bucket_counts(configured, args.max_buckets) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
-                    debug!("Add file {} to watch", path.to_string_lossy());
+    let listen = match args.listen.is_empty() {
+        true => vec![SocketAddr::new(args.bind_address, args.port)],
+        false => args.listen.clone(),
+    };
 
-                    self.log_files.insert(
-                        path,
-                        LogFileMeta {
-                            file_position: 0,
-                            inode,
-                        },
-                    );
-                }
-                Err(e) => error!("{:?}", e),
+    let settings = Settings {
+        pattern: args.log_path,
+        buckets,
+        size_buckets: exponential_buckets(64.0, 4.0, 8),
+        bucket_overrides: args.buckets_for.into_iter().collect(),
+        with_exemplars: args.with_exemplars,
+        json_multiline: args.json_multiline,
+        lenient_json: args.lenient_json,
+        sum_count_only: args.sum_count_only,
+        reset_interval: args.reset_interval,
+        warmup: args.warmup,
+        max_files: args.max_files,
+        with_protocol: args.with_protocol,
+        with_scheme: args.with_scheme,
+        with_cache_control: args.with_cache_control,
+        with_hour_label: args.with_hour_label,
+        with_upstream_status: args.with_upstream_status,
+        sample_timestamps: args.sample_timestamps,
+        separate_499: args.separate_499,
+        with_pid_info: args.with_pid_info,
+        path_depth: args.path_depth,
+        query_label_paths: args.query_label_paths,
+        tail_buffer: args.tail_buffer,
+        read_buffer_bytes: args.read_buffer_bytes,
+        powered_by: !args.no_powered_by,
+        scrape_rate_limit: args.scrape_rate_limit,
+        missing_file_grace: args.missing_file_grace,
+        status_fields: args.status_fields,
+        debug_token: args.debug_token,
+        metrics_path: args.metrics_path,
+        max_catchup_bytes: args.max_catchup_bytes,
+        line_exclude: args.line_exclude_regex,
+        line_include: args.line_include_regex,
+        host_groups: args.host_group,
+        label_sanitize: args.label_sanitize,
+        disabled_metrics: args.disable_metric.into_iter().collect(),
+        descriptions,
+        static_labels: args.static_label,
+        #[cfg(feature = "journald")]
+        journald_unit: args.journald_unit,
+        #[cfg(feature = "journald")]
+        journald_lines: if args.start_at_end {
+            0
+        } else {
+            args.journald_lines
+        },
+        startup: StartupSettings {
+            listen: listen.clone(),
+            start_at_end: args.start_at_end,
+            tail_bytes: args.tail_bytes,
+            pushgateway_url: args.pushgateway_url.clone(),
+            push_interval: args.push_interval,
+            push_job: args.push_job.clone(),
+            push_instance: args.push_instance.clone(),
+            federate_from: args.federate_from.clone(),
+            federate_interval: args.federate_interval,
+            http_sources: args.http_source.clone(),
+            http_source_interval: args.http_source_interval,
+            shutdown_timeout: args.shutdown_timeout,
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: args.otlp_endpoint.clone(),
+            #[cfg(feature = "remote-write")]
+            remote_write_url: args.remote_write_url.clone(),
+        },
+    };
+
+    if let Some(sample) = &args.validate {
+        match validate::validate_sample(sample, args.validate_lines, settings) {
+            Ok(report) => {
+                print!("{}", report);
+                std::process::exit(if report.is_usable() { 0 } else { 1 });
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
         }
     }
 
-    fn handle_file_rotation(path: &PathBuf, meta: &mut LogFileMeta) -> Result<(), String> {
-        let metadata =
-            std::fs::metadata(path).map_err(|e| format!("Failed to get file metadata: {}", e))?;
+    let state = Arc::new(Mutex::new(MetricsState::new(settings)));
 
-        let inode = metadata.ino();
+    // Initial discovery, so a pattern matching nothing is reported right away
+    {
+        let mut state = state.lock().unwrap();
+        state.update_files_map();
 
-        if meta.inode != inode || meta.file_position > metadata.len() {
-            debug!("Rotation file {} detected", path.to_string_lossy());
+        // Files appearing later are new and still read from the start
+        if args.start_at_end {
+            state.skip_existing_content();
+        }
 
-            meta.file_position = 0;
-            meta.inode = inode;
+        if let Some(tail_bytes) = args.tail_bytes {
+            state.skip_to_tail(tail_bytes);
         }
+    }
 
-        Ok(())
+    if let Some(interval) = args.reset_interval {
+        warn!(
+            "Reset interval {:?} enabled: metrics are exposed as per-window deltas, not cumulative values",
+            interval
+        );
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately, skip it so the first window is a full interval
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = state.lock().unwrap().rotate_window() {
+                    error!("Error rotating metrics window: {}", e);
+                }
+            }
+        });
     }
 
-    fn read_new_entries(&mut self) -> Result<HashMap<MetricLabels, Vec<f64>>, String> {
-        for (path, meta) in &mut self.log_files {
-            if !path.exists() {
-                warn!("Failed to find file {}. Skipped", path.to_string_lossy());
-                continue;
+    let federation_sources = match args
+        .federate_from
+        .iter()
+        .map(|url| FederationSource::new(url).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(sources) => sources,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !federation_sources.is_empty() {
+        info!(
+            "Federating {} exporters every {:?}",
+            federation_sources.len(),
+            args.federate_interval
+        );
+
+        state.lock().unwrap().federated = federation_sources
+            .iter()
+            .map(|source| FederatedScrape::new(&source.url))
+            .collect();
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(args.federate_interval);
+
+            loop {
+                ticker.tick().await;
+
+                federate::scrape_all(&federation_sources, &state, args.federate_interval).await;
+            }
+        });
+    }
+
+    let mut http_sources = match args
+        .http_source
+        .iter()
+        .map(|url| HttpSource::new(url))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(sources) => sources,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !http_sources.is_empty() {
+        info!(
+            "Tailing {} files over HTTP every {:?}",
+            http_sources.len(),
+            args.http_source_interval
+        );
+
+        state.lock().unwrap().http_sources = http_sources
+            .iter()
+            .map(|source| HttpTail::new(&source.url))
+            .collect();
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(args.http_source_interval);
+
+            loop {
+                ticker.tick().await;
+
+                http_source::fetch_all(&mut http_sources, &state, args.http_source_interval).await;
             }
+        });
+    }
 
-            if let Err(e) = MetricsState::handle_file_rotation(path, meta) {
+    let pushgateway = match &args.pushgateway_url {
+        Some(url) => match Pushgateway::new(url, &args.push_job, args.push_instance.as_deref()) {
+            Ok(pushgateway) => Some(Arc::new(pushgateway)),
+            Err(e) => {
                 error!("{}", e);
-                continue;
+                std::process::exit(1);
             }
+        },
+        None => None,
+    };
 
-            let file = OpenOptions::new().read(true).open(path).map_err(|e| {
-                format!("Failed to open log file {}: {}", path.to_string_lossy(), e)
-            })?;
+    if let Some(pushgateway) = &pushgateway {
+        info!("Pushing metrics every {:?}", args.push_interval);
 
-            let mut reader = BufReader::new(file);
+        let state = Arc::clone(&state);
+        let pushgateway = Arc::clone(pushgateway);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(args.push_interval);
 
-            reader
-                .seek(SeekFrom::Start(meta.file_position))
-                .map_err(|e| {
-                    format!(
-                        "Failed to seek to position in file {}: {}",
-                        path.to_string_lossy(),
-                        e
-                    )
-                })?;
+            loop {
+                ticker.tick().await;
+
+                // The Pushgateway rejects pushed samples with timestamps
+                let body = {
+                    let mut state = state.lock().unwrap();
+                    state
+                        .collect()
+                        .map(|metrics| render_text(&state, &metrics, false, false))
+                };
+
+                match body {
+                    Ok(body) => {
+                        if let Err(e) = pushgateway.push(&body).await {
+                            error!("Error pushing metrics: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Error reading log entries: {}", e),
+                }
+            }
+        });
+    }
 
-            let mut line = String::new();
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let exporter = match otlp::OtlpExporter::new(endpoint) {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        info!("Exporting metrics via OTLP every {:?}", args.push_interval);
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(args.push_interval);
+            let mut exported_window = None;
 
             loop {
-                let bytes_read = reader
-                    .read_line(&mut line)
-                    .map_err(|e| format!("Failed to read line: {}", e))?;
+                ticker.tick().await;
 
-                if bytes_read == 0 {
-                    break;
-                }
+                let body = {
+                    let mut state = state.lock().unwrap();
 
-                if !line.trim().is_empty() {
-                    match serde_json::from_str::<NginxLogEntry>(&line) {
-                        Ok(entry) => {
-                            if let Ok(duration) = entry.nginx.time.request.parse::<f64>() {
-                                let labels = MetricLabels {
-                                    method: entry.nginx.access.method,
-                                    path: entry.nginx.access.url,
-                                    status_code: get_status_label(entry.http.response.status_code)?
-                                        .to_string(),
-                                    host: entry.nginx.access.host,
-                                };
-                                self.metrics
-                                    .entry(labels)
-                                    .or_insert_with(Vec::new)
-                                    .push(duration);
-                            }
+                    // Windows are deltas, sending one twice would count it twice
+                    if state.settings.reset_interval.is_some() {
+                        let window_end = otlp::window_end(&state);
+                        if window_end.is_none() || window_end == exported_window {
+                            continue;
                         }
-                        Err(e) => {
-                            error!("Failed to parse log line: {} - Error: {}", line.trim(), e);
+                        exported_window = window_end;
+                    }
+
+                    state
+                        .collect()
+                        .map(|metrics| otlp::encode_metrics(&state, &metrics))
+                };
+
+                match body {
+                    Ok(body) => {
+                        if let Err(e) = exporter.export(&body).await {
+                            error!("Error exporting metrics via OTLP: {}", e);
                         }
                     }
+                    Err(e) => error!("Error reading log entries: {}", e),
                 }
+            }
+        });
+    }
 
-                meta.file_position += bytes_read as u64;
-                line.clear();
+    #[cfg(feature = "remote-write")]
+    if let Some(url) = &args.remote_write_url {
+        let writer = match remote_write::RemoteWriter::new(url) {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
-        }
+        };
 
-        Ok(self.metrics.clone())
-    }
-}
+        info!(
+            "Sending metrics via remote write every {:?}",
+            args.push_interval
+        );
 
-fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
-    let mut buckets = Vec::with_capacity(count);
-    let mut current = start;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(args.push_interval);
 
-    for _ in 0..count {
-        buckets.push(current);
-        current *= factor;
+            loop {
+                ticker.tick().await;
+
+                let body = {
+                    let mut state = state.lock().unwrap();
+                    state
+                        .collect()
+                        .and_then(|metrics| remote_write::encode_metrics(&state, &metrics))
+                };
+
+                match body {
+                    Ok(body) => {
+                        if let Err(e) = writer.write(&body).await {
+                            error!("Error sending metrics via remote write: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Error reading log entries: {}", e),
+                }
+            }
+        });
     }
 
-    buckets
-}
+    let app = server::router(Arc::clone(&state));
 
-fn calculate_histogram_buckets(data: &[f64], buckets: &[f64]) -> Vec<usize> {
-    let mut counts = vec![0; buckets.len()];
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    let mut servers = JoinSet::new();
 
-    for &value in data {
-        for (i, &bucket) in buckets.iter().enumerate() {
-            if value <= bucket {
-                counts[i] += 1;
+    for addr in listen {
+        let listener = match server::bind(addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind to {}: {}", addr, e);
+                std::process::exit(1);
             }
-        }
-    }
+        };
 
-    counts
-}
+        info!("Server listening on {}", addr);
 
-async fn metrics_handler(state: Arc<Mutex<MetricsState>>) -> (StatusCode, String) {
-    let mut state = state.lock().unwrap();
+        let mut shutdown = shutdown_rx.clone();
+        servers.spawn(
+            axum::serve(listener, app.clone())
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown.changed().await;
+                })
+                .into_future(),
+        );
+    }
 
-    state.update_files_map();
+    shutdown_signal().await;
+    let _ = shutdown_tx.send(());
 
-    let buckets = exponential_buckets(0.005, 2.0, 10);
+    drain_servers(&mut servers, args.shutdown_timeout).await;
 
-    let mut output: Vec<String> = vec![
-        "# HELP nginx_http_request_duration_seconds Request duration in seconds".to_string(),
-        "# TYPE nginx_http_request_duration_seconds histogram".to_string(),
-    ];
+    // Pushed series outlive the exporter unless their group is deleted
+    if let Some(pushgateway) = pushgateway {
+        match pushgateway.delete().await {
+            Ok(()) => info!("Deleted pushed metrics from the Pushgateway"),
+            Err(e) => error!("Error deleting pushed metrics: {}", e),
+        }
+    }
+}
 
-    let metrics_map = match state.read_new_entries() {
-        Ok(m) => m,
-        Err(e) => {
-            error!("Error reading log entries: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("# Error: {}\n", e),
-            );
+// Waits for the servers to finish their in-flight requests. A stuck scrape must not
+// keep the process from exiting, so the wait ends after `timeout` and connections still
+// open are closed as the process exits.
+async fn drain_servers(servers: &mut JoinSet<std::io::Result<()>>, timeout: Duration) {
+    let drain = async {
+        while let Some(result) = servers.join_next().await {
+            if let Ok(Err(e)) = result {
+                error!("Server error: {}", e);
+            }
         }
     };
 
-    for (labels, durations) in metrics_map.iter() {
-        let sum: f64 = durations.iter().sum();
-        let count = durations.len();
-        let mut sorted_durations = durations.clone();
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        warn!("Connections still open after {:?}, closing them", timeout);
+        servers.abort_all();
+    }
+}
 
-        sorted_durations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
 
-        // Calculate histogram buckets
-        let bucket_counts = calculate_histogram_buckets(durations, &buckets);
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 
-        let label_str = format!(
-            "method=\"{}\",path=\"{}\",status_code=\"{}\",host=\"{}\"",
-            labels.method, labels.path, labels.status_code, labels.host
-        );
+    info!("Shutting down");
+}
 
-        // Output histogram buckets
-        for (i, &bucket_limit) in buckets.iter().enumerate() {
-            output.push(format!(
-                "nginx_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
-                label_str, bucket_limit, bucket_counts[i]
-            ));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Add +Inf bucket (all values)
-        output.push(format!(
-            "nginx_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
-            label_str, count
+    fn buckets_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "nginx-exporter-test-{}-{}",
+            std::process::id(),
+            name
         ));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
 
-        // Output sum and count
-        output.push(format!(
-            "nginx_http_request_duration_seconds_sum{{{}}} {}",
-            label_str, sum
-        ));
+    #[test]
+    fn buckets_are_loaded_from_lines_or_a_json_array() {
+        let lines = buckets_file("buckets.txt", "# seconds\n0.1\n\n0.5\n2.5\n");
+        assert_eq!(load_buckets_file(&lines), Ok(vec![0.1, 0.5, 2.5]));
 
-        output.push(format!(
-            "nginx_http_request_duration_seconds_count{{{}}} {}",
-            label_str, count
-        ));
+        let array = buckets_file("buckets.json", "[0.1, 0.5, 2.5]");
+        assert_eq!(load_buckets_file(&array), Ok(vec![0.1, 0.5, 2.5]));
     }
 
-    (StatusCode::OK, output.join("\n"))
-}
+    #[test]
+    fn buckets_file_must_be_increasing() {
+        let path = buckets_file("decreasing.txt", "0.5\n0.1\n");
+        assert!(load_buckets_file(&path)
+            .unwrap_err()
+            .contains("strictly increasing"));
 
-async fn custom_header_middleware<B>(mut response: Response<B>) -> Response<B> {
-    response.headers_mut().insert(
-        "X-Powered-By",
-        HeaderValue::from_static("nginx-prometheus-exporter"),
-    );
-    response
-}
+        let empty = buckets_file("empty.txt", "# nothing\n");
+        assert!(load_buckets_file(&empty).is_err());
+    }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init_from_env(env_logger::Env::default().filter_or("LOG_LEVEL", "info"));
+    #[test]
+    fn bucket_counts_above_the_cap_are_rejected() {
+        let forty = exponential_buckets(0.001, 1.5, 40);
+        let ten = exponential_buckets(0.005, 2.0, 10);
 
-    let args = Args::parse();
+        assert!(check_bucket_counts([("default duration buckets", ten.as_slice())], 10).is_ok());
 
-    info!("Starting Nginx Prometheus Exporter");
-    info!("Log file: {:?}", args.log_path);
+        let error = check_bucket_counts(
+            [
+                ("default duration buckets", ten.as_slice()),
+                ("nginx_http_request_size_bytes", forty.as_slice()),
+            ],
+            32,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "nginx_http_request_size_bytes is configured with 40 buckets, more than --max-buckets 32"
+        );
+    }
 
-    let state = Arc::new(Mutex::new(MetricsState::new(args.log_path)));
+    #[test]
+    fn metrics_path_must_be_a_free_absolute_path() {
+        assert_eq!(
+            parse_metrics_path("/nginx/metrics"),
+            Ok("/nginx/metrics".to_string())
+        );
+        assert!(parse_metrics_path("metrics").is_err());
+        assert!(parse_metrics_path("/metrics/:id").is_err());
+        assert!(parse_metrics_path("/").is_err());
+        assert!(parse_metrics_path("/metrics.json").is_err());
+    }
 
-    let app = Router::new()
-        .route(
+    #[tokio::test]
+    async fn shutdown_closes_stuck_requests_after_the_timeout() {
+        let slow = axum::Router::new().route(
             "/metrics",
-            get({
-                let state = Arc::clone(&state);
-                move || metrics_handler(state)
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                "too late"
             }),
-        )
-        .layer(middleware::map_response(custom_header_middleware));
+        );
 
-    let addr = format!("0.0.0.0:{}", args.port);
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .expect("Failed to bind to address");
+        let listener = server::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, mut shutdown_rx) = tokio::sync::watch::channel(());
+        let mut servers = JoinSet::new();
+        servers.spawn(
+            axum::serve(listener, slow)
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown_rx.changed().await;
+                })
+                .into_future(),
+        );
 
-    info!("Server listening on {}", addr);
+        let scrape = tokio::spawn(async move {
+            nginx_prometheus_exporter::push::send_request(
+                &addr.to_string(),
+                "GET",
+                "/metrics",
+                &[],
+                &[],
+            )
+            .await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = std::time::Instant::now();
+        shutdown_tx.send(()).unwrap();
+        drain_servers(&mut servers, Duration::from_millis(200)).await;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!scrape.is_finished());
+        scrape.abort();
+    }
 
-    axum::serve(listener, app)
-        .await
-        .expect("Server failed to start");
+    #[test]
+    fn host_group_patterns_only_expand_stars() {
+        let (regex, group) = parse_host_group("*.customers.example.com => customers").unwrap();
+        assert_eq!(group, "customers");
+        assert!(regex.is_match("acme.customers.example.com"));
+        assert!(regex.is_match("a.b.customers.example.com"));
+        assert!(!regex.is_match("customers.example.com"));
+        assert!(!regex.is_match("acme.customersXexample.com"));
+        assert!(!regex.is_match("acme.customers.example.com.evil"));
+
+        assert!(parse_host_group("*.example.com").is_err());
+        assert!(parse_host_group("=>group").is_err());
+    }
 }
@@ -0,0 +1,1907 @@
+use crate::federate::merge_federated;
+use crate::state::{file_label, MetricsState};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+pub const METRICS_PREFIX: &str = "nginx";
+
+pub fn duration_metric(prefix: &str) -> String {
+    format!("{}_http_request_duration_seconds", prefix)
+}
+
+pub fn request_size_metric(prefix: &str) -> String {
+    format!("{}_http_request_size_bytes", prefix)
+}
+
+pub const REQUEST_SIZE_HELP: &str = "Request size in bytes, including request line and headers";
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+pub struct MetricLabels {
+    pub method: String,
+    pub path: String,
+    pub status_code: String,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hour: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+}
+
+impl MetricLabels {
+    pub fn pairs(&self) -> Vec<(&'static str, &str)> {
+        let mut pairs = vec![
+            ("method", self.method.as_str()),
+            ("path", self.path.as_str()),
+            ("status_code", self.status_code.as_str()),
+            ("host", self.host.as_str()),
+        ];
+
+        if let Some(protocol) = &self.protocol {
+            pairs.push(("protocol", protocol));
+        }
+
+        if let Some(scheme) = &self.scheme {
+            pairs.push(("scheme", scheme));
+        }
+
+        if let Some(cache_control) = &self.cache_control {
+            pairs.push(("cache_control", cache_control));
+        }
+
+        if let Some(hour) = &self.hour {
+            pairs.push(("hour", hour));
+        }
+
+        if let Some(query) = &self.query {
+            pairs.push(("query", query));
+        }
+
+        pairs
+    }
+}
+
+/// Per-metric HELP and unit overrides, keyed by the metric name as exposed.
+#[derive(Debug, Clone, Default)]
+pub struct MetricDescriptions {
+    pub help: HashMap<String, String>,
+    pub units: HashMap<String, String>,
+}
+
+impl MetricDescriptions {
+    /// OpenMetrics requires the family name to end with its unit.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, unit) in &self.units {
+            let family = name.strip_suffix("_total").unwrap_or(name);
+
+            if !family.ends_with(&format!("_{}", unit)) {
+                return Err(format!(
+                    "Metric {} must end with its unit suffix _{}",
+                    name, unit
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unit<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        if let Some(unit) = self.units.get(name) {
+            return Some(unit);
+        }
+
+        let family = name.strip_suffix("_total").unwrap_or(name);
+
+        ["seconds", "bytes"]
+            .into_iter()
+            .find(|unit| family.ends_with(&format!("_{}", unit)))
+    }
+
+    pub fn help<'a>(&'a self, name: &str, default: &'a str) -> &'a str {
+        self.help.get(name).map_or(default, String::as_str)
+    }
+
+    /// HELP and TYPE lines for a metric family, plus UNIT in OpenMetrics mode.
+    pub fn header(
+        &self,
+        name: &str,
+        help: &str,
+        metric_type: &str,
+        openmetrics: bool,
+    ) -> Vec<String> {
+        // OpenMetrics names counter families without the _total sample suffix
+        let family = match metric_type {
+            "counter" if openmetrics => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+
+        let mut lines = vec![
+            format!("# HELP {} {}", family, self.help(name, help)),
+            format!("# TYPE {} {}", family, metric_type),
+        ];
+
+        if let Some(unit) = self.unit(name).filter(|_| openmetrics) {
+            lines.push(format!("# UNIT {} {}", family, unit));
+        }
+
+        lines
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    pub timestamp: f64,
+}
+
+pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
+    let mut buckets = Vec::with_capacity(count);
+    let mut current = start;
+
+    for _ in 0..count {
+        buckets.push(current);
+        current *= factor;
+    }
+
+    buckets
+}
+
+/// Observations of one series: every value, so any bucket layout can be computed at
+/// render time, or only their running sum and count with --sum-count-only.
+#[derive(Debug, Clone)]
+pub enum Observations {
+    Values(Vec<f64>),
+    SumCount { sum: f64, count: usize },
+}
+
+impl Observations {
+    pub fn new(sum_count_only: bool) -> Self {
+        match sum_count_only {
+            true => Observations::SumCount { sum: 0.0, count: 0 },
+            false => Observations::Values(Vec::new()),
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        match self {
+            Observations::Values(values) => values.push(value),
+            Observations::SumCount { sum, count } => {
+                *sum += value;
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn sum(&self) -> f64 {
+        match self {
+            Observations::Values(values) => values.iter().sum(),
+            Observations::SumCount { sum, .. } => *sum,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            Observations::Values(values) => values.len(),
+            Observations::SumCount { count, .. } => *count,
+        }
+    }
+
+    /// Cumulative counts for `buckets`, `None` when only the sum and count were kept.
+    pub fn bucket_counts(&self, buckets: &[f64]) -> Option<Vec<usize>> {
+        match self {
+            Observations::Values(values) => Some(calculate_histogram_buckets(values, buckets)),
+            Observations::SumCount { .. } => None,
+        }
+    }
+}
+
+pub fn calculate_histogram_buckets(data: &[f64], buckets: &[f64]) -> Vec<usize> {
+    let mut counts = vec![0; buckets.len()];
+
+    for &value in data {
+        for (i, &bucket) in buckets.iter().enumerate() {
+            if value <= bucket {
+                counts[i] += 1;
+            }
+        }
+    }
+
+    counts
+}
+
+fn format_exemplar(exemplar: &Exemplar) -> String {
+    format!(
+        " # {{trace_id=\"{}\"}} {} {:.3}",
+        escape_label_value(&exemplar.trace_id),
+        exemplar.value,
+        exemplar.timestamp
+    )
+}
+
+/// Additions to the samples of a series besides their value.
+#[derive(Clone, Copy, Default)]
+pub struct SampleExtras<'a> {
+    // Only passed in for OpenMetrics scrapes
+    pub exemplars: Option<&'a HashMap<MetricLabels, Vec<Option<Exemplar>>>>,
+    // Unix time of the latest entry per series, with --sample-timestamps
+    pub timestamps: Option<&'a HashMap<MetricLabels, f64>>,
+}
+
+/// Renders the request duration histogram in the text exposition format,
+/// with series sorted by labels so the output is stable between scrapes.
+pub fn render_exposition(
+    metrics: &HashMap<MetricLabels, Observations>,
+    extras: SampleExtras,
+    buckets: Option<&[f64]>,
+    prefix: &str,
+    descriptions: &MetricDescriptions,
+    openmetrics: bool,
+) -> String {
+    render_histogram(
+        &duration_metric(prefix),
+        "Request duration in seconds",
+        metrics,
+        extras,
+        buckets,
+        descriptions,
+        openmetrics,
+    )
+}
+
+/// Renders one histogram family from its observations. Without buckets (--sum-count-only)
+/// it is a summary without quantiles, i.e. only `_sum` and `_count`.
+pub fn render_histogram(
+    name: &str,
+    help: &str,
+    metrics: &HashMap<MetricLabels, Observations>,
+    extras: SampleExtras,
+    buckets: Option<&[f64]>,
+    descriptions: &MetricDescriptions,
+    openmetrics: bool,
+) -> String {
+    let kind = match buckets {
+        Some(_) => "histogram",
+        None => "summary",
+    };
+    let mut output = descriptions.header(name, help, kind, openmetrics);
+
+    let mut series: Vec<_> = metrics.iter().collect();
+    series.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (labels, observations) in series {
+        let sum = observations.sum();
+        let count = observations.count();
+
+        let label_str = labels
+            .pairs()
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        // Milliseconds in the Prometheus text format, seconds in OpenMetrics
+        let timestamp = extras
+            .timestamps
+            .and_then(|timestamps| timestamps.get(labels))
+            .map(|&timestamp| match openmetrics {
+                true => format!(" {:.3}", timestamp),
+                false => format!(" {}", (timestamp * 1000.0).round() as i64),
+            })
+            .unwrap_or_default();
+
+        let slots = extras.exemplars.and_then(|exemplars| exemplars.get(labels));
+        let exemplar_str = |i: usize| {
+            slots
+                .and_then(|slots| slots.get(i))
+                .and_then(Option::as_ref)
+                .map(format_exemplar)
+                .unwrap_or_default()
+        };
+
+        // Output histogram buckets
+        if let Some((buckets, bucket_counts)) = buckets.and_then(|buckets| {
+            observations
+                .bucket_counts(buckets)
+                .map(|counts| (buckets, counts))
+        }) {
+            for (i, &bucket_limit) in buckets.iter().enumerate() {
+                output.push(format!(
+                    "{}_bucket{{{},le=\"{}\"}} {}{}{}",
+                    name,
+                    label_str,
+                    bucket_limit,
+                    bucket_counts[i],
+                    timestamp,
+                    exemplar_str(i)
+                ));
+            }
+
+            // Add +Inf bucket (all values)
+            output.push(format!(
+                "{}_bucket{{{},le=\"+Inf\"}} {}{}{}",
+                name,
+                label_str,
+                count,
+                timestamp,
+                exemplar_str(buckets.len())
+            ));
+        }
+
+        // Output sum and count
+        output.push(format!(
+            "{}_sum{{{}}} {}{}",
+            name, label_str, sum, timestamp
+        ));
+        output.push(format!(
+            "{}_count{{{}}} {}{}",
+            name, label_str, count, timestamp
+        ));
+    }
+
+    output.join("\n")
+}
+
+// Labels set by the exporter itself, which static labels must not shadow
+const RESERVED_LABELS: [&str; 25] = [
+    "method",
+    "path",
+    "status_code",
+    "upstream_status_code",
+    "status",
+    "host",
+    "protocol",
+    "scheme",
+    "cache_control",
+    "hour",
+    "query",
+    "le",
+    "pid",
+    "file",
+    "type",
+    "patterns",
+    "format",
+    "status_mode",
+    "status_fields",
+    "version",
+    "commit",
+    "rustc",
+    "build_timestamp",
+    "metric",
+    "source",
+];
+
+/// Checks static label names against the Prometheus label name syntax and the
+/// labels the exporter sets itself.
+pub fn validate_static_labels(labels: &[(String, String)]) -> Result<(), String> {
+    for (name, _) in labels {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+        if !valid || name.starts_with("__") {
+            return Err(format!("Invalid static label name '{}'", name));
+        }
+
+        if RESERVED_LABELS.contains(&name.as_str()) {
+            return Err(format!(
+                "Static label '{}' clashes with a label set by the exporter",
+                name
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// End of the series part of a sample line. Label values may hold spaces and braces,
+// so the label set ends at the first `}` outside quotes
+fn series_end(line: &str) -> Option<usize> {
+    let start = line.find(['{', ' '])?;
+
+    if !line[start..].starts_with('{') {
+        return Some(start);
+    }
+
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in line[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return Some(start + i + 1),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Splits a sample line of the text exposition into its series (`name{labels}`) and
+/// value. A timestamp after the value is dropped.
+pub fn split_sample(line: &str) -> Option<(&str, f64)> {
+    let end = series_end(line)?;
+    let value = line[end..].split_whitespace().next()?.parse().ok()?;
+
+    Some((&line[..end], value))
+}
+
+/// Name and unescaped label pairs of a series as returned by `split_sample`.
+pub fn parse_series(series: &str) -> (&str, Vec<(String, String)>) {
+    let Some((name, labels)) = series.split_once('{') else {
+        return (series, Vec::new());
+    };
+
+    let mut pairs = Vec::new();
+    let mut chars = labels.chars();
+
+    // name="value" pairs separated by commas, up to the closing brace
+    loop {
+        let label: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        let label = label.trim_start_matches(',').trim();
+
+        if label.is_empty() || chars.next() != Some('"') {
+            break;
+        }
+
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+
+        pairs.push((label.to_string(), value));
+    }
+
+    (name, pairs)
+}
+
+/// How label values taken from the logs are exposed when they contain quotes,
+/// backslashes or control characters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelSanitize {
+    // Kept as logged, escaped in the text exposition
+    #[default]
+    Escape,
+    // Replaced with `_` when the entry is recorded, so every output sees the same value
+    Replace,
+}
+
+fn is_awkward_label_char(c: char) -> bool {
+    c == '"' || c == '\\' || c.is_control()
+}
+
+/// Replaces quotes, backslashes and control characters with `_`.
+pub fn replace_label_chars(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if is_awkward_label_char(c) { '_' } else { c })
+        .collect()
+}
+
+/// Applies --label-sanitize to a label value, leaving clean values untouched.
+pub fn sanitize_label_value(value: &mut String, mode: LabelSanitize) {
+    if mode == LabelSanitize::Replace && value.contains(is_awkward_label_char) {
+        *value = replace_label_chars(value);
+    }
+}
+
+/// Adds the static labels to every sample line of a text exposition.
+fn with_static_labels(text: String, labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return text;
+    }
+
+    let label_str = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    text.lines()
+        .map(|line| {
+            if line.starts_with('#') {
+                return line.to_string();
+            }
+
+            // The metric name ends at its label set or, without one, at the value
+            match line.find(['{', ' ']) {
+                Some(i) if line[i..].starts_with('{') => {
+                    format!("{}{{{},{}", &line[..i], label_str, &line[i + 1..])
+                }
+                Some(i) => format!("{}{{{}}}{}", &line[..i], label_str, &line[i..]),
+                None => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Drops the HELP, TYPE and UNIT lines and the samples of disabled families from a
+/// text exposition.
+fn without_disabled(text: String, disabled: &BTreeSet<String>) -> String {
+    if disabled.is_empty() {
+        return text;
+    }
+
+    let is_disabled = |name: &str| {
+        // OpenMetrics names counter families without the _total sample suffix
+        disabled.contains(name)
+            || disabled.contains(&format!("{}_total", name))
+            || ["_bucket", "_sum", "_count", "_created", "_total"]
+                .iter()
+                .any(|suffix| {
+                    name.strip_suffix(suffix)
+                        .is_some_and(|family| disabled.contains(family))
+                })
+    };
+
+    text.lines()
+        .filter(|line| {
+            let name = match line.strip_prefix("# ") {
+                Some(comment) => comment.split(' ').nth(1),
+                None => line.split(['{', ' ']).next(),
+            };
+            !name.is_some_and(is_disabled)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Every section besides the request histograms: counters, info and exporter metrics
+fn render_counter_sections(
+    state: &MetricsState,
+    active_series: usize,
+    openmetrics: bool,
+) -> [String; 6] {
+    [
+        render_requests(state, openmetrics),
+        render_host_requests(state, openmetrics),
+        render_cache_status(state, openmetrics),
+        render_upstream_status(state, openmetrics),
+        render_worker_info(state, openmetrics),
+        render_exporter_metrics(state, active_series, openmetrics),
+    ]
+}
+
+/// Renders the counter and gauge families of `render_text` in the text format, with
+/// static labels added and disabled families left out, for the protobuf encoding.
+pub fn render_counters_text(state: &MetricsState, active_series: usize) -> String {
+    let sections = render_counter_sections(state, active_series, false)
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    without_disabled(
+        with_static_labels(sections, &state.settings.static_labels),
+        &state.settings.disabled_metrics,
+    )
+}
+
+/// Renders the full text exposition: request metrics followed by the exporter's own.
+/// Exemplars are only included in OpenMetrics mode, and sample timestamps only
+/// when `with_timestamps` is set along with --sample-timestamps. Every line ends with
+/// a newline; the OpenMetrics `# EOF` marker is left to the caller.
+pub fn render_text(
+    state: &MetricsState,
+    metrics: &HashMap<MetricLabels, Observations>,
+    openmetrics: bool,
+    with_timestamps: bool,
+) -> String {
+    let timestamps = Some(state.current_sample_timestamps())
+        .filter(|_| with_timestamps && state.settings.sample_timestamps);
+
+    let sections = [
+        render_exposition(
+            metrics,
+            SampleExtras {
+                exemplars: Some(state.current_exemplars()).filter(|_| openmetrics),
+                timestamps,
+            },
+            state
+                .settings
+                .histogram_buckets(&duration_metric(METRICS_PREFIX)),
+            METRICS_PREFIX,
+            &state.settings.descriptions,
+            openmetrics,
+        ),
+        render_histogram(
+            &request_size_metric(METRICS_PREFIX),
+            REQUEST_SIZE_HELP,
+            state.current_request_sizes(),
+            SampleExtras {
+                exemplars: None,
+                timestamps,
+            },
+            state
+                .settings
+                .histogram_buckets(&request_size_metric(METRICS_PREFIX)),
+            &state.settings.descriptions,
+            openmetrics,
+        ),
+    ]
+    .into_iter()
+    .chain(render_counter_sections(state, metrics.len(), openmetrics))
+    .filter(|section| !section.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n");
+
+    let mut sections = with_static_labels(sections, &state.settings.static_labels);
+
+    // Sources are scraped in the text format only, the server doesn't negotiate others
+    if !openmetrics && !state.federated.is_empty() {
+        let (merged, skipped) = merge_federated(&sections, &state.federated);
+        let skipped = with_static_labels(
+            render_federation_skipped(state, &skipped),
+            &state.settings.static_labels,
+        );
+        sections = [merged, skipped].join("\n");
+    }
+
+    let mut output = without_disabled(sections, &state.settings.disabled_metrics);
+
+    // Both formats require the last line to be terminated as well
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Renders the request histograms as JSON Lines, one object per series with its
+/// labels, cumulative bucket counts, sum and count.
+pub fn render_json_lines(
+    state: &MetricsState,
+    metrics: &HashMap<MetricLabels, Observations>,
+) -> String {
+    let duration_name = duration_metric(METRICS_PREFIX);
+    let size_name = request_size_metric(METRICS_PREFIX);
+
+    let histograms = [
+        (&duration_name, metrics),
+        (&size_name, state.current_request_sizes()),
+    ];
+
+    let mut output = String::new();
+
+    for (name, series) in histograms
+        .into_iter()
+        .filter(|(name, _)| state.settings.metric_enabled(name))
+    {
+        let buckets = state.settings.histogram_buckets(name);
+
+        let mut labels: Vec<_> = series.keys().collect();
+        labels.sort();
+
+        for labels in labels {
+            let values = &series[labels];
+
+            let mut pairs: serde_json::Map<String, serde_json::Value> = labels
+                .pairs()
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.into()))
+                .collect();
+            for (key, value) in &state.settings.static_labels {
+                pairs.insert(key.clone(), value.as_str().into());
+            }
+
+            // Empty with --sum-count-only
+            let bucket_counts: Vec<_> = buckets
+                .and_then(|buckets| Some((buckets, values.bucket_counts(buckets)?)))
+                .map(|(buckets, counts)| {
+                    buckets
+                        .iter()
+                        .map(|bucket| bucket.to_string())
+                        .chain(["+Inf".to_string()])
+                        .zip(counts.into_iter().chain([values.count()]))
+                        .map(|(le, count)| serde_json::json!({"le": le, "count": count}))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let line = serde_json::json!({
+                "name": name,
+                "labels": pairs,
+                "buckets": bucket_counts,
+                "sum": values.sum(),
+                "count": values.count(),
+            });
+            output.push_str(&line.to_string());
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+/// Renders request counts with the histogram labels. Unlike the histogram's `_count`,
+/// entries without a request time are counted too. Omitted until an entry was recorded.
+fn render_requests(state: &MetricsState, openmetrics: bool) -> String {
+    let requests = state.current_requests();
+
+    if requests.is_empty() {
+        return String::new();
+    }
+
+    let name = format!("{}_http_requests_total", METRICS_PREFIX);
+
+    let mut output =
+        state
+            .settings
+            .descriptions
+            .header(&name, "Requests by labels", "counter", openmetrics);
+
+    let mut series: Vec<_> = requests.iter().collect();
+    series.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (labels, count) in series {
+        let label_str = labels
+            .pairs()
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        output.push(format!("{}{{{}}} {}", name, label_str, count));
+    }
+
+    output.join("\n")
+}
+
+/// Renders request counts by host only, omitted until an entry was recorded.
+fn render_host_requests(state: &MetricsState, openmetrics: bool) -> String {
+    let host_requests = state.current_host_requests();
+
+    if host_requests.is_empty() {
+        return String::new();
+    }
+
+    let name = format!("{}_http_requests_by_host_total", METRICS_PREFIX);
+
+    let mut output =
+        state
+            .settings
+            .descriptions
+            .header(&name, "Requests by host", "counter", openmetrics);
+
+    for (host, count) in host_requests {
+        output.push(format!(
+            "{}{{host=\"{}\"}} {}",
+            name,
+            escape_label_value(host),
+            count
+        ));
+    }
+
+    output.join("\n")
+}
+
+/// Renders request counts by `$upstream_cache_status`, omitted when no entry had one.
+fn render_cache_status(state: &MetricsState, openmetrics: bool) -> String {
+    let cache_statuses = state.current_cache_statuses();
+
+    if cache_statuses.is_empty() {
+        return String::new();
+    }
+
+    let name = format!("{}_http_cache_status_total", METRICS_PREFIX);
+
+    let mut output = state.settings.descriptions.header(
+        &name,
+        "Requests by upstream cache status",
+        "counter",
+        openmetrics,
+    );
+
+    for ((status, host), count) in cache_statuses {
+        output.push(format!(
+            "{}{{status=\"{}\",host=\"{}\"}} {}",
+            name,
+            status,
+            escape_label_value(host),
+            count
+        ));
+    }
+
+    output.join("\n")
+}
+
+/// Renders upstream response counts by `$upstream_status` class next to the final
+/// status class, only with --with-upstream-status and once an entry had one.
+fn render_upstream_status(state: &MetricsState, openmetrics: bool) -> String {
+    let upstream_statuses = state.current_upstream_statuses();
+
+    if upstream_statuses.is_empty() {
+        return String::new();
+    }
+
+    let name = format!("{}_http_upstream_responses_total", METRICS_PREFIX);
+
+    let mut output = state.settings.descriptions.header(
+        &name,
+        "Upstream responses by upstream status and final status",
+        "counter",
+        openmetrics,
+    );
+
+    for ((upstream_status, status, host), count) in upstream_statuses {
+        output.push(format!(
+            "{}{{upstream_status_code=\"{}\",status_code=\"{}\",host=\"{}\"}} {}",
+            name,
+            upstream_status,
+            status,
+            escape_label_value(host),
+            count
+        ));
+    }
+
+    output.join("\n")
+}
+
+/// Renders the number of families of each federated source left out of the sums.
+fn render_federation_skipped(state: &MetricsState, skipped: &[usize]) -> String {
+    let mut output = state.settings.descriptions.header(
+        "nginx_exporter_federation_families_skipped",
+        "Families of a --federate-from source left out of the sums in the last exposition because their type or histogram buckets differ from the local ones",
+        "gauge",
+        false,
+    );
+
+    for (scrape, skipped) in state.federated.iter().zip(skipped) {
+        output.push(format!(
+            "nginx_exporter_federation_families_skipped{{source=\"{}\"}} {}",
+            escape_label_value(&scrape.source),
+            skipped
+        ));
+    }
+
+    output.join("\n")
+}
+
+/// Renders one value-1 series per nginx worker pid seen in the logs, only with --with-pid-info.
+fn render_worker_info(state: &MetricsState, openmetrics: bool) -> String {
+    if !state.settings.with_pid_info {
+        return String::new();
+    }
+
+    let name = format!("{}_worker_info", METRICS_PREFIX);
+
+    let mut output = state.settings.descriptions.header(
+        &name,
+        "Nginx worker process seen in the access logs",
+        "gauge",
+        openmetrics,
+    );
+
+    for pid in &state.worker_pids {
+        output.push(format!("{}{{pid=\"{}\"}} 1", name, escape_label_value(pid)));
+    }
+
+    output.join("\n")
+}
+
+/// Resident set size of the exporter process, from `VmRSS` in `/proc/self/status`, which
+/// unlike `/proc/self/statm` doesn't need the page size. `None` outside Linux.
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+/// Renders the exporter's own metrics (file watching, parsing health and cardinality).
+/// `active_series` is the number of label sets in the exposed duration histogram.
+pub fn render_exporter_metrics(
+    state: &MetricsState,
+    active_series: usize,
+    openmetrics: bool,
+) -> String {
+    let descriptions = &state.settings.descriptions;
+    let mut output: Vec<String> = Vec::new();
+
+    output.extend(descriptions.header(
+        "nginx_exporter_start_time_seconds",
+        "Unix time the exporter process started at",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_start_time_seconds {}",
+        state.start_time
+    ));
+
+    if let Some(resident_memory) = resident_memory_bytes() {
+        output.extend(descriptions.header(
+            "nginx_exporter_resident_memory_bytes",
+            "Resident memory size of the exporter process",
+            "gauge",
+            openmetrics,
+        ));
+        output.push(format!(
+            "nginx_exporter_resident_memory_bytes {}",
+            resident_memory
+        ));
+    }
+
+    let format = match state.settings.json_multiline {
+        true => "json-multiline",
+        false => "json",
+    };
+
+    output.extend(descriptions.header(
+        "nginx_exporter_config_info",
+        "Effective runtime configuration of the exporter",
+        "gauge",
+        openmetrics,
+    ));
+    // 499 is either folded into 4xx or kept apart by --separate-499
+    let status_mode = match state.settings.separate_499 {
+        true => "classes+499",
+        false => "classes",
+    };
+
+    output.push(format!(
+        "nginx_exporter_config_info{{patterns=\"{}\",format=\"{}\",status_mode=\"{}\",status_fields=\"{}\"}} 1",
+        escape_label_value(&state.settings.pattern),
+        format,
+        status_mode,
+        escape_label_value(&state.settings.status_fields.join(","))
+    ));
+
+    // Set by build.rs
+    output.extend(descriptions.header(
+        "nginx_exporter_build_info",
+        "Version, git commit, rustc version and build time of the exporter",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_build_info{{version=\"{}\",commit=\"{}\",rustc=\"{}\",build_timestamp=\"{}\"}} 1",
+        env!("CARGO_PKG_VERSION"),
+        env!("BUILD_GIT_COMMIT"),
+        env!("BUILD_RUSTC_VERSION"),
+        env!("BUILD_TIMESTAMP")
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_files_watched",
+        "Number of log files currently watched",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_files_watched {}",
+        state.log_files.len()
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_files_skipped_total",
+        "Matching files not watched because of the --max-files limit",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_files_skipped_total {}",
+        state.files_skipped_total
+    ));
+
+    let mut file_mtimes: Vec<_> = state
+        .log_files
+        .iter()
+        .map(|(path, meta)| (file_label(path), meta.mtime))
+        .collect();
+    file_mtimes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_file_mtime_seconds",
+        "Last modification time of a watched log file",
+        "gauge",
+        openmetrics,
+    ));
+    for (file, mtime) in file_mtimes {
+        output.push(format!(
+            "nginx_exporter_file_mtime_seconds{{file=\"{}\"}} {}",
+            escape_label_value(&file),
+            mtime
+        ));
+    }
+
+    let mut backlogs: Vec<_> = state
+        .log_files
+        .iter()
+        .map(|(path, meta)| (file_label(path), meta.backlog_bytes))
+        .collect();
+    backlogs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_file_backlog_bytes",
+        "Bytes of a watched log file left unread after the last read cycle",
+        "gauge",
+        openmetrics,
+    ));
+    for (file, backlog) in backlogs {
+        output.push(format!(
+            "nginx_exporter_file_backlog_bytes{{file=\"{}\"}} {}",
+            escape_label_value(&file),
+            backlog
+        ));
+    }
+
+    let mut since_reads: Vec<_> = state
+        .log_files
+        .iter()
+        .map(|(path, meta)| (file_label(path), meta.last_read.elapsed().as_secs_f64()))
+        .collect();
+    since_reads.sort_by(|a, b| a.0.cmp(&b.0));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_seconds_since_last_read",
+        "Seconds since a read cycle last got new bytes from a watched log file",
+        "gauge",
+        openmetrics,
+    ));
+    for (file, seconds) in since_reads {
+        output.push(format!(
+            "nginx_exporter_seconds_since_last_read{{file=\"{}\"}} {}",
+            escape_label_value(&file),
+            seconds
+        ));
+    }
+
+    if !state.federated.is_empty() {
+        output.extend(descriptions.header(
+            "nginx_exporter_federation_up",
+            "Whether the last scrape of a --federate-from source succeeded",
+            "gauge",
+            openmetrics,
+        ));
+        for scrape in &state.federated {
+            output.push(format!(
+                "nginx_exporter_federation_up{{source=\"{}\"}} {}",
+                escape_label_value(&scrape.source),
+                u8::from(scrape.up)
+            ));
+        }
+    }
+
+    output.extend(descriptions.header(
+        "nginx_exporter_ingest_lag_seconds",
+        "Age of the last entry read from the file furthest behind, 0 when all are caught up",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_ingest_lag_seconds {}",
+        state.ingest_lag_seconds()
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_parse_errors_total",
+        "Log entries that could not be parsed, by error type",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_parse_errors_total{{type=\"syntax\"}} {}",
+        state.syntax_errors_total
+    ));
+    output.push(format!(
+        "nginx_exporter_parse_errors_total{{type=\"structural\"}} {}",
+        state.structural_errors_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_bytes_read_total",
+        "Bytes of log data read",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_bytes_read_total {}",
+        state.bytes_read_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_bytes_skipped_on_rotation_total",
+        "Bytes of rotated log files left unread when the exporter moved on to the new file",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_bytes_skipped_on_rotation_total {}",
+        state.bytes_skipped_on_rotation_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_file_open_errors_total",
+        "Read cycles in which a watched log file was skipped because it couldn't be opened",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_file_open_errors_total {}",
+        state.file_open_errors_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_read_throughput_bytes_per_second",
+        "Bytes of log data read per second over the last read cycle",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_read_throughput_bytes_per_second {}",
+        state.read_throughput
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_json_repairs_total",
+        "Log lines rewritten by --lenient-json before parsing",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_json_repairs_total {}",
+        state.json_repairs_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_parse_success_ratio",
+        "Share of log entries parsed successfully during the last read cycle with new entries",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_parse_success_ratio {}",
+        state.parse_success_ratio
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_unknown_status_total",
+        "Log entries with a status code outside 100-599, counted as status_code=\"other\"",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_unknown_status_total {}",
+        state.unknown_status_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_missing_duration_total",
+        "Log entries without a usable request time, left out of the duration histogram only",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_missing_duration_total {}",
+        state.missing_duration_total
+    ));
+
+    output.extend(descriptions.header(
+        "nginx_exporter_lines_excluded_total",
+        "Log lines skipped by --line-exclude-regex or --line-include-regex",
+        "counter",
+        openmetrics,
+    ));
+    output.push(format!(
+        "nginx_exporter_lines_excluded_total {}",
+        state.lines_excluded_total
+    ));
+
+    // Every exposed histogram is listed, so alerts see 0 rather than a missing series
+    let over_max_bucket = [
+        duration_metric(METRICS_PREFIX),
+        request_size_metric(METRICS_PREFIX),
+    ]
+    .into_iter()
+    .filter(|name| state.settings.metric_enabled(name))
+    .map(|name| {
+        let total = state.over_max_bucket_total.get(&name).copied();
+        (name, total.unwrap_or_default())
+    });
+
+    output.extend(descriptions.header(
+        "nginx_exporter_observations_over_max_bucket_total",
+        "Observations above the largest finite bucket of a histogram, only counted in +Inf",
+        "counter",
+        openmetrics,
+    ));
+    for (name, total) in over_max_bucket {
+        output.push(format!(
+            "nginx_exporter_observations_over_max_bucket_total{{metric=\"{}\"}} {}",
+            name, total
+        ));
+    }
+
+    output.extend(descriptions.header(
+        "nginx_exporter_active_series",
+        "Distinct label sets of the request duration histogram",
+        "gauge",
+        openmetrics,
+    ));
+    output.push(format!("nginx_exporter_active_series {}", active_series));
+
+    output.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{log_line, record, settings, temp_dir};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn labels(path: &str) -> MetricLabels {
+        MetricLabels {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status_code: "2xx".to_string(),
+            host: "example.com".to_string(),
+            protocol: None,
+            scheme: None,
+            cache_control: None,
+            hour: None,
+            query: None,
+        }
+    }
+
+    fn observations(values: &[f64]) -> Observations {
+        Observations::Values(values.to_vec())
+    }
+
+    #[test]
+    fn render_exposition_counts_buckets_cumulatively() {
+        let metrics = HashMap::from([(labels("/a"), observations(&[0.05, 0.5, 5.0]))]);
+
+        let output = render_exposition(
+            &metrics,
+            SampleExtras::default(),
+            Some(&[0.1, 1.0]),
+            METRICS_PREFIX,
+            &MetricDescriptions::default(),
+            false,
+        );
+
+        let series = r#"method="GET",path="/a",status_code="2xx",host="example.com""#;
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "# HELP nginx_http_request_duration_seconds Request duration in seconds"
+                    .to_string(),
+                "# TYPE nginx_http_request_duration_seconds histogram".to_string(),
+                format!(
+                    "nginx_http_request_duration_seconds_bucket{{{},le=\"0.1\"}} 1",
+                    series
+                ),
+                format!(
+                    "nginx_http_request_duration_seconds_bucket{{{},le=\"1\"}} 2",
+                    series
+                ),
+                format!(
+                    "nginx_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 3",
+                    series
+                ),
+                format!("nginx_http_request_duration_seconds_sum{{{}}} 5.55", series),
+                format!("nginx_http_request_duration_seconds_count{{{}}} 3", series),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_gauges_report_the_mtime_with_escaped_basenames() {
+        let dir = temp_dir("file-gauges");
+        let path = dir.join("odd\"name\\.log");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let settings = settings(&dir.join("*.log").to_string_lossy());
+        let mut state = MetricsState::new(settings);
+        state.update_files_map();
+
+        let output = render_text(&state, &HashMap::new(), false, false);
+        assert!(output.contains(
+            "nginx_exporter_file_mtime_seconds{file=\"odd\\\"name\\\\.log\"} 1700000000\n"
+        ));
+        assert!(
+            output.contains("nginx_exporter_file_backlog_bytes{file=\"odd\\\"name\\\\.log\"} 0\n")
+        );
+    }
+
+    #[test]
+    fn config_info_reflects_the_configuration() {
+        let mut settings = settings("/var/log/nginx/*.log");
+        settings.separate_499 = true;
+        settings.status_fields = vec!["status_code".to_string(), "status".to_string()];
+        let state = MetricsState::new(settings);
+
+        let output = render_text(&state, &HashMap::new(), false, false);
+        assert!(output.contains(
+            "nginx_exporter_config_info{patterns=\"/var/log/nginx/*.log\",format=\"json\",status_mode=\"classes+499\",status_fields=\"status_code,status\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn exemplars_are_attached_to_buckets_in_openmetrics_only() {
+        let mut settings = settings("unused");
+        settings.buckets = vec![0.1, 1.0];
+        settings.with_exemplars = true;
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[r#"{"http":{"response":{"status_code":"200"}},"nginx":{"access":{"method":"GET","url":"/a","host":"example.com"},"time":{"request":"0.05"},"http":{"trace_id":"abc123"}}}"#.to_string()],
+        );
+
+        let metrics = state.metrics.clone();
+        let openmetrics = render_text(&state, &metrics, true, false);
+        let bucket = openmetrics
+            .lines()
+            .find(|line| line.contains("_bucket{") && line.contains("le=\"0.1\""))
+            .unwrap();
+        assert!(
+            bucket.contains("} 1 # {trace_id=\"abc123\"} 0.05 "),
+            "{}",
+            bucket
+        );
+
+        // Only the bucket the observation fell into carries it
+        assert_eq!(openmetrics.matches("trace_id").count(), 1);
+        assert!(!render_text(&state, &metrics, false, false).contains("trace_id"));
+    }
+
+    fn render(metrics: &HashMap<MetricLabels, Observations>) -> String {
+        render_exposition(
+            metrics,
+            SampleExtras::default(),
+            Some(&[0.1, 1.0]),
+            METRICS_PREFIX,
+            &MetricDescriptions::default(),
+            false,
+        )
+    }
+
+    #[test]
+    fn render_exposition_snapshot_without_series() {
+        assert_eq!(
+            render(&HashMap::new()),
+            "# HELP nginx_http_request_duration_seconds Request duration in seconds\n\
+             # TYPE nginx_http_request_duration_seconds histogram"
+        );
+    }
+
+    #[test]
+    fn render_exposition_snapshot_single_series() {
+        let metrics = HashMap::from([(labels("/a"), observations(&[0.25]))]);
+
+        assert_eq!(
+            render(&metrics),
+            r#"# HELP nginx_http_request_duration_seconds Request duration in seconds
+# TYPE nginx_http_request_duration_seconds histogram
+nginx_http_request_duration_seconds_bucket{method="GET",path="/a",status_code="2xx",host="example.com",le="0.1"} 0
+nginx_http_request_duration_seconds_bucket{method="GET",path="/a",status_code="2xx",host="example.com",le="1"} 1
+nginx_http_request_duration_seconds_bucket{method="GET",path="/a",status_code="2xx",host="example.com",le="+Inf"} 1
+nginx_http_request_duration_seconds_sum{method="GET",path="/a",status_code="2xx",host="example.com"} 0.25
+nginx_http_request_duration_seconds_count{method="GET",path="/a",status_code="2xx",host="example.com"} 1"#
+        );
+    }
+
+    #[test]
+    fn render_exposition_snapshot_unicode_and_escaped_paths() {
+        let metrics = HashMap::from([
+            (labels("/café/日本"), observations(&[0.5])),
+            (labels("/a\"b\\c\nd"), observations(&[0.5])),
+        ]);
+
+        let counts: Vec<_> = render(&metrics)
+            .lines()
+            .filter(|line| line.contains("_count{"))
+            .map(str::to_string)
+            .collect();
+
+        // Unicode is kept as is, quotes, backslashes and newlines are escaped
+        assert_eq!(
+            counts,
+            [
+                r#"nginx_http_request_duration_seconds_count{method="GET",path="/a\"b\\c\nd",status_code="2xx",host="example.com"} 1"#,
+                r#"nginx_http_request_duration_seconds_count{method="GET",path="/café/日本",status_code="2xx",host="example.com"} 1"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn help_overrides_and_units_in_openmetrics() {
+        let descriptions = MetricDescriptions {
+            help: HashMap::from([(
+                "nginx_http_request_duration_seconds".to_string(),
+                "Latency of API requests".to_string(),
+            )]),
+            units: HashMap::from([(
+                "nginx_http_request_size_bytes".to_string(),
+                "bytes".to_string(),
+            )]),
+        };
+        assert!(descriptions.validate().is_ok());
+
+        assert_eq!(
+            descriptions.header(
+                "nginx_http_request_duration_seconds",
+                "Request duration in seconds",
+                "histogram",
+                true
+            ),
+            [
+                "# HELP nginx_http_request_duration_seconds Latency of API requests",
+                "# TYPE nginx_http_request_duration_seconds histogram",
+                "# UNIT nginx_http_request_duration_seconds seconds",
+            ]
+        );
+
+        // No UNIT in the Prometheus text format, and counters drop _total in OpenMetrics
+        assert_eq!(
+            descriptions
+                .header(
+                    "nginx_exporter_read_bytes_total",
+                    "Bytes read",
+                    "counter",
+                    false
+                )
+                .len(),
+            2
+        );
+        assert_eq!(
+            descriptions.header(
+                "nginx_exporter_read_bytes_total",
+                "Bytes read",
+                "counter",
+                true
+            ),
+            [
+                "# HELP nginx_exporter_read_bytes Bytes read",
+                "# TYPE nginx_exporter_read_bytes counter",
+                "# UNIT nginx_exporter_read_bytes bytes",
+            ]
+        );
+
+        let invalid = MetricDescriptions {
+            units: HashMap::from([(
+                "nginx_http_requests_total".to_string(),
+                "seconds".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn each_histogram_uses_its_own_buckets() {
+        let mut settings = settings("unused");
+        settings.bucket_overrides = HashMap::from([
+            (
+                "nginx_http_request_duration_seconds".to_string(),
+                vec![0.01, 0.1, 1.0],
+            ),
+            (
+                "nginx_http_request_size_bytes".to_string(),
+                vec![256.0, 1024.0],
+            ),
+        ]);
+        let mut state = MetricsState::new(settings);
+
+        let mut line: serde_json::Value =
+            serde_json::from_str(&log_line("/a", "200", "0.05")).unwrap();
+        line["nginx"]["bytes"] = serde_json::json!({ "request_length": "512" });
+        record(&mut state, &[line.to_string()]);
+
+        let output = render_text(&state, &state.metrics, false, false);
+        let bounds = |name: &str| {
+            output
+                .lines()
+                .filter(|line| line.starts_with(&format!("{}_bucket", name)))
+                .map(|line| {
+                    line.split("le=\"")
+                        .nth(1)
+                        .unwrap()
+                        .split('"')
+                        .next()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            bounds("nginx_http_request_duration_seconds"),
+            ["0.01", "0.1", "1", "+Inf"]
+        );
+        assert_eq!(
+            bounds("nginx_http_request_size_bytes"),
+            ["256", "1024", "+Inf"]
+        );
+        // Other histograms keep the defaults
+        assert_eq!(
+            state.settings.buckets_for("nginx_http_response_size_bytes"),
+            state.settings.size_buckets
+        );
+    }
+
+    #[test]
+    fn worker_info_lists_logged_pids_when_enabled() {
+        let mut line: serde_json::Value =
+            serde_json::from_str(&log_line("/a", "200", "0.1")).unwrap();
+        line["nginx"]["pid"] = serde_json::json!("4242");
+        let lines = [line.to_string(), log_line("/a", "200", "0.1")];
+
+        let mut state = MetricsState::new(settings("unused"));
+        record(&mut state, &lines);
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(!output.contains("nginx_worker_info"));
+
+        let mut settings = settings("unused");
+        settings.with_pid_info = true;
+        let mut state = MetricsState::new(settings);
+        record(&mut state, &lines);
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(
+            output.contains("# TYPE nginx_worker_info gauge\nnginx_worker_info{pid=\"4242\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn static_labels_are_on_every_sample() {
+        let mut settings = settings("unused");
+        settings.static_labels = vec![
+            ("env".to_string(), "prod".to_string()),
+            ("region".to_string(), "eu".to_string()),
+        ];
+        let mut state = MetricsState::new(settings);
+        record(&mut state, &[log_line("/a", "200", "0.1")]);
+
+        let output = render_text(&state, &state.metrics, false, false);
+        let samples: Vec<_> = output
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert!(samples.len() > 10);
+        for sample in samples {
+            assert!(sample.contains(r#"{env="prod",region="eu""#), "{}", sample);
+        }
+
+        assert!(validate_static_labels(&[("job".to_string(), "web".to_string())]).is_ok());
+        assert!(validate_static_labels(&[("1st".to_string(), "x".to_string())]).is_err());
+        assert!(validate_static_labels(&[("__name__".to_string(), "x".to_string())]).is_err());
+        assert!(validate_static_labels(&[("path".to_string(), "x".to_string())]).is_err());
+    }
+
+    #[test]
+    fn cache_statuses_are_counted_per_host() {
+        let with_cache_status = |status: &str| {
+            let mut line: serde_json::Value =
+                serde_json::from_str(&log_line("/a", "200", "0.1")).unwrap();
+            line["nginx"]["upstream"] = serde_json::json!({ "cache_status": status });
+            line.to_string()
+        };
+
+        let mut state = MetricsState::new(settings("unused"));
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(!output.contains("nginx_http_cache_status_total"));
+
+        record(
+            &mut state,
+            &[
+                with_cache_status("HIT"),
+                with_cache_status("HIT"),
+                with_cache_status("MISS"),
+                with_cache_status("-"),
+                log_line("/a", "200", "0.1"),
+            ],
+        );
+
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(output.contains(concat!(
+            "# TYPE nginx_http_cache_status_total counter\n",
+            "nginx_http_cache_status_total{status=\"HIT\",host=\"example.com\"} 2\n",
+            "nginx_http_cache_status_total{status=\"MISS\",host=\"example.com\"} 1\n",
+        )));
+    }
+
+    #[test]
+    fn active_series_counts_distinct_label_sets() {
+        let mut state = MetricsState::new(settings("unused"));
+        record(
+            &mut state,
+            &[
+                log_line("/a", "200", "0.1"),
+                log_line("/a", "200", "0.2"),
+                log_line("/b", "200", "0.1"),
+                log_line("/b", "500", "0.1"),
+            ],
+        );
+
+        let output = render_text(&state, &state.metrics, false, false);
+        assert_eq!(state.metrics.len(), 3);
+        assert!(
+            output.contains("\nnginx_exporter_active_series 3\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn resident_memory_is_reported_on_linux() {
+        let state = MetricsState::new(settings("unused"));
+        let output = render_text(&state, &state.metrics, false, false);
+
+        let rss = output
+            .lines()
+            .find_map(|line| line.strip_prefix("nginx_exporter_resident_memory_bytes "))
+            .unwrap();
+        assert!(rss.parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    fn observations_above_the_top_bucket_are_counted() {
+        let mut settings = settings("unused");
+        settings.buckets = vec![0.1, 1.0];
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[
+                log_line("/a", "200", "0.5"),
+                log_line("/a", "200", "1.0"),
+                log_line("/a", "200", "2.5"),
+                log_line("/a", "200", "30"),
+            ],
+        );
+
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(output.contains(
+            "nginx_exporter_observations_over_max_bucket_total{metric=\"nginx_http_request_duration_seconds\"} 2\n"
+        ), "{}", output);
+    }
+
+    #[test]
+    fn disabled_families_are_left_out() {
+        let mut settings = settings("unused");
+        settings.disabled_metrics = [
+            "nginx_http_request_duration_seconds".to_string(),
+            "nginx_exporter_bytes_read_total".to_string(),
+        ]
+        .into_iter()
+        .collect();
+        let mut state = MetricsState::new(settings);
+        record(&mut state, &[log_line("/a", "200", "0.1")]);
+
+        let output = render_text(&state, &state.metrics, false, false);
+        // Also gone from the exporter's own series about it
+        assert!(
+            !output.contains("nginx_http_request_duration_seconds"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("nginx_exporter_bytes_read_total"));
+        assert!(output.contains(
+            "nginx_http_requests_total{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\"} 1\n"
+        ));
+        assert!(output.contains("nginx_exporter_files_watched"));
+    }
+
+    #[test]
+    fn sample_timestamps_are_the_latest_entry_time_in_milliseconds() {
+        let at = |msec: &str| {
+            let mut line: serde_json::Value =
+                serde_json::from_str(&log_line("/a", "200", "0.1")).unwrap();
+            line["nginx"]["time"]["msec"] = serde_json::json!(msec);
+            line.to_string()
+        };
+
+        let mut settings = settings("unused");
+        settings.buckets = vec![1.0];
+        settings.sample_timestamps = true;
+        let mut state = MetricsState::new(settings);
+        record(&mut state, &[at("1704078000.250"), at("1704078005.123")]);
+
+        let output = render_text(&state, &state.metrics, false, true);
+        assert!(output.contains(
+            "nginx_http_request_duration_seconds_count{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\"} 2 1704078005123\n"
+        ), "{}", output);
+
+        // Seconds in OpenMetrics, nothing unless asked for
+        let output = render_text(&state, &state.metrics, true, true);
+        assert!(output.contains("} 2 1704078005.123\n"));
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(!output.contains("1704078005"));
+    }
+
+    #[test]
+    fn upstream_statuses_are_counted_next_to_the_final_status() {
+        let with_upstream = |status: &str, upstream_status: &str| {
+            let mut line: serde_json::Value =
+                serde_json::from_str(&log_line("/a", status, "0.1")).unwrap();
+            line["nginx"]["upstream"] = serde_json::json!({ "status": upstream_status });
+            line.to_string()
+        };
+
+        let mut settings = settings("unused");
+        settings.with_upstream_status = true;
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[
+                // Served from cache after the upstream failed
+                with_upstream("200", "500"),
+                // Retried on a second upstream
+                with_upstream("200", "502, 200"),
+                with_upstream("200", "-"),
+            ],
+        );
+
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(output.contains(concat!(
+            "# TYPE nginx_http_upstream_responses_total counter\n",
+            "nginx_http_upstream_responses_total{upstream_status_code=\"2xx\",status_code=\"2xx\",host=\"example.com\"} 1\n",
+            "nginx_http_upstream_responses_total{upstream_status_code=\"5xx\",status_code=\"2xx\",host=\"example.com\"} 2\n",
+        )), "{}", output);
+    }
+
+    #[test]
+    fn requests_by_host_sum_over_every_path() {
+        let on_host = |path: &str, host: &str| {
+            let mut line: serde_json::Value =
+                serde_json::from_str(&log_line(path, "200", "0.1")).unwrap();
+            line["nginx"]["access"]["host"] = serde_json::json!(host);
+            line.to_string()
+        };
+
+        let mut state = MetricsState::new(settings("unused"));
+        record(
+            &mut state,
+            &[
+                on_host("/a", "api.example.com"),
+                on_host("/b", "api.example.com"),
+                on_host("/c", "api.example.com"),
+                on_host("/a", "www.example.com"),
+            ],
+        );
+
+        let output = render_text(&state, &state.metrics, false, false);
+        assert!(
+            output.contains(concat!(
+                "nginx_http_requests_by_host_total{host=\"api.example.com\"} 3\n",
+                "nginx_http_requests_by_host_total{host=\"www.example.com\"} 1\n",
+            )),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn label_sanitize_replace_or_escape() {
+        let line = log_line(r#"/a\"b\\c\td"#, "200", "0.1");
+        let series = |state: &MetricsState| {
+            render_text(state, &state.metrics, false, false)
+                .lines()
+                .find(|line| line.starts_with("nginx_http_requests_total{"))
+                .unwrap()
+                .to_string()
+        };
+
+        let mut state = MetricsState::new(settings("unused"));
+        record(&mut state, std::slice::from_ref(&line));
+        assert!(
+            series(&state).contains(r#"path="/a\"b\\c	d""#),
+            "{}",
+            series(&state)
+        );
+
+        let mut replace = settings("unused");
+        replace.label_sanitize = LabelSanitize::Replace;
+        let mut state = MetricsState::new(replace);
+        record(&mut state, &[line]);
+        assert!(
+            series(&state).contains(r#"path="/a_b_c_d""#),
+            "{}",
+            series(&state)
+        );
+    }
+
+    #[test]
+    fn seconds_since_last_read_resets_on_new_data() {
+        let dir = temp_dir("since-last-read");
+        let path = dir.join("access.log");
+        std::fs::write(&path, "").unwrap();
+
+        let mut state = MetricsState::new(settings(&format!("{}/*.log", dir.display())));
+        state.update_files_map();
+        state.read_new_entries().unwrap();
+
+        let since = |state: &MetricsState| {
+            render_text(state, &state.metrics, false, false)
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix(
+                        "nginx_exporter_seconds_since_last_read{file=\"access.log\"} ",
+                    )
+                })
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+        };
+
+        // As if nothing had been read for a minute
+        state.log_files.get_mut(&path).unwrap().last_read -= Duration::from_secs(60);
+        state.read_new_entries().unwrap();
+        assert!(since(&state) >= 60.0);
+
+        std::fs::write(&path, log_line("/a", "200", "0.1") + "\n").unwrap();
+        state.read_new_entries().unwrap();
+        assert!(since(&state) < 5.0);
+    }
+
+    #[test]
+    fn sum_count_only_renders_no_buckets() {
+        let mut settings = settings("unused");
+        settings.sum_count_only = true;
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[log_line("/a", "200", "0.25"), log_line("/a", "200", "1.5")],
+        );
+
+        let series = state.metrics.values().next().unwrap();
+        assert!(matches!(series, Observations::SumCount { count: 2, .. }));
+        assert_eq!(series.bucket_counts(&[1.0]), None);
+
+        let text = render_text(&state, &state.metrics, false, false);
+        assert!(!text.contains("nginx_http_request_duration_seconds_bucket"));
+        assert!(text.contains(
+            "nginx_http_request_duration_seconds_sum{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\"} 1.75"
+        ));
+        assert!(text.contains(
+            "nginx_http_request_duration_seconds_count{method=\"GET\",path=\"/a\",status_code=\"2xx\",host=\"example.com\"} 2"
+        ));
+    }
+
+    #[test]
+    fn build_info_reports_the_package_version() {
+        let state = MetricsState::new(settings("unused"));
+        let text = render_text(&state, &state.metrics, false, false);
+
+        let line = text
+            .lines()
+            .find(|line| line.starts_with("nginx_exporter_build_info{"))
+            .unwrap();
+        assert!(line.contains(&format!("version=\"{}\"", env!("CARGO_PKG_VERSION"))));
+        assert!(line.contains(&format!("commit=\"{}\"", env!("BUILD_GIT_COMMIT"))));
+        assert!(line.ends_with("} 1"));
+    }
+}
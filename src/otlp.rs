@@ -0,0 +1,402 @@
+use crate::metrics::{
+    duration_metric, request_size_metric, MetricLabels, Observations, METRICS_PREFIX,
+    REQUEST_SIZE_HELP,
+};
+use crate::push::{send_request, split_http_url};
+use crate::state::MetricsState;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, AggregationTemporality, Histogram, HistogramDataPoint, Metric,
+    NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prost::Message;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sends metrics to an OpenTelemetry collector over OTLP/HTTP with protobuf encoding.
+pub struct OtlpExporter {
+    host: String,
+    path: String,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: &str) -> Result<Self, String> {
+        let (host, mut path) =
+            split_http_url(endpoint).map_err(|e| format!("Invalid OTLP endpoint: {}", e))?;
+        path.push_str("/v1/metrics");
+
+        Ok(Self { host, path })
+    }
+
+    pub async fn export(&self, body: &[u8]) -> Result<(), String> {
+        send_request(
+            &self.host,
+            "POST",
+            &self.path,
+            &[("Content-Type", "application/x-protobuf")],
+            body,
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+fn nanos(seconds: f64) -> u64 {
+    (seconds * 1e9) as u64
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn attributes(labels: &MetricLabels) -> Vec<KeyValue> {
+    labels
+        .pairs()
+        .into_iter()
+        .map(|(key, value)| string_attribute(key, value))
+        .collect()
+}
+
+/// Start and end of the data points: the window with --reset-interval, otherwise
+/// the time since the exporter started.
+#[derive(Clone, Copy)]
+struct Interval {
+    start: u64,
+    now: u64,
+    temporality: AggregationTemporality,
+}
+
+fn counter(
+    name: &str,
+    description: &str,
+    points: Vec<(Vec<KeyValue>, u64)>,
+    interval: &Interval,
+) -> Metric {
+    let data_points = points
+        .into_iter()
+        .map(|(attributes, value)| NumberDataPoint {
+            attributes,
+            start_time_unix_nano: interval.start,
+            time_unix_nano: interval.now,
+            value: Some(number_data_point::Value::AsInt(value as i64)),
+            ..Default::default()
+        })
+        .collect();
+
+    Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        data: Some(metric::Data::Sum(Sum {
+            data_points,
+            aggregation_temporality: interval.temporality as i32,
+            is_monotonic: true,
+        })),
+        ..Default::default()
+    }
+}
+
+/// An explicit-bucket histogram with per-bucket rather than cumulative counts.
+fn histogram(
+    name: &str,
+    description: &str,
+    unit: &str,
+    series: &HashMap<MetricLabels, Observations>,
+    buckets: &[f64],
+    interval: &Interval,
+) -> Metric {
+    let mut series: Vec<_> = series.iter().collect();
+    series.sort_by(|a, b| a.0.cmp(b.0));
+
+    let data_points = series
+        .into_iter()
+        .map(|(labels, values)| {
+            // Without bounds (--sum-count-only) the single bucket holds every observation
+            let cumulative = values.bucket_counts(buckets).unwrap_or_default();
+
+            let mut bucket_counts = Vec::with_capacity(buckets.len() + 1);
+            let mut previous = 0;
+            for count in cumulative.into_iter().chain([values.count()]) {
+                bucket_counts.push((count - previous) as u64);
+                previous = count;
+            }
+
+            HistogramDataPoint {
+                attributes: attributes(labels),
+                start_time_unix_nano: interval.start,
+                time_unix_nano: interval.now,
+                count: values.count() as u64,
+                sum: Some(values.sum()),
+                bucket_counts,
+                explicit_bounds: buckets.to_vec(),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    Metric {
+        name: name.to_string(),
+        description: description.to_string(),
+        unit: unit.to_string(),
+        data: Some(metric::Data::Histogram(Histogram {
+            data_points,
+            aggregation_temporality: interval.temporality as i32,
+        })),
+        ..Default::default()
+    }
+}
+
+/// Builds an ExportMetricsServiceRequest from the request metrics and the exporter's
+/// own counters. Prometheus buckets map to explicit-bucket histograms.
+///
+/// With --reset-interval the request metrics are the deltas of the last window, stamped
+/// with its start and end, so each window must be exported once (see `window_end`).
+pub fn encode_metrics(
+    state: &MetricsState,
+    metrics: &HashMap<MetricLabels, Observations>,
+) -> Vec<u8> {
+    let duration_name = duration_metric(METRICS_PREFIX);
+    let size_name = request_size_metric(METRICS_PREFIX);
+    let requests_name = format!("{}_http_requests_total", METRICS_PREFIX);
+    let descriptions = &state.settings.descriptions;
+
+    let now = nanos(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default(),
+    );
+
+    // Everything else accumulates since startup
+    let cumulative = Interval {
+        start: nanos(state.start_time),
+        now,
+        temporality: AggregationTemporality::Cumulative,
+    };
+
+    let requests = match &state.last_window {
+        Some(window) => Interval {
+            start: nanos(window.start_time),
+            now: nanos(window.end_time),
+            temporality: AggregationTemporality::Delta,
+        },
+        None => cumulative,
+    };
+
+    let mut request_counts: Vec<_> = state.current_requests().iter().collect();
+    request_counts.sort();
+
+    let parse_errors = vec![
+        (
+            vec![string_attribute("type", "syntax")],
+            state.syntax_errors_total,
+        ),
+        (
+            vec![string_attribute("type", "structural")],
+            state.structural_errors_total,
+        ),
+    ];
+
+    let metrics = vec![
+        histogram(
+            &duration_name,
+            descriptions.help(&duration_name, "Request duration in seconds"),
+            "s",
+            metrics,
+            state
+                .settings
+                .histogram_buckets(&duration_name)
+                .unwrap_or_default(),
+            &requests,
+        ),
+        histogram(
+            &size_name,
+            descriptions.help(&size_name, REQUEST_SIZE_HELP),
+            "By",
+            state.current_request_sizes(),
+            state
+                .settings
+                .histogram_buckets(&size_name)
+                .unwrap_or_default(),
+            &requests,
+        ),
+        counter(
+            &requests_name,
+            descriptions.help(&requests_name, "Requests by labels"),
+            request_counts
+                .into_iter()
+                .map(|(labels, &count)| (attributes(labels), count))
+                .collect(),
+            &requests,
+        ),
+        counter(
+            "nginx_exporter_parse_errors_total",
+            descriptions.help(
+                "nginx_exporter_parse_errors_total",
+                "Log entries that could not be parsed, by error type",
+            ),
+            parse_errors,
+            &cumulative,
+        ),
+        counter(
+            "nginx_exporter_files_skipped_total",
+            descriptions.help(
+                "nginx_exporter_files_skipped_total",
+                "Matching files not watched because of the --max-files limit",
+            ),
+            vec![(Vec::new(), state.files_skipped_total)],
+            &cumulative,
+        ),
+    ];
+
+    let metrics = metrics
+        .into_iter()
+        .filter(|metric| state.settings.metric_enabled(&metric.name))
+        .collect();
+
+    // Static labels describe the source, so they become resource attributes
+    let resource_attributes = [
+        ("service.name", env!("CARGO_PKG_NAME")),
+        ("service.version", env!("CARGO_PKG_VERSION")),
+    ]
+    .into_iter()
+    .chain(
+        state
+            .settings
+            .static_labels
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    )
+    .map(|(key, value)| string_attribute(key, value))
+    .collect();
+
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: resource_attributes,
+                ..Default::default()
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: env!("CARGO_PKG_NAME").to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    ..Default::default()
+                }),
+                metrics,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+    .encode_to_vec()
+}
+
+/// End of the last closed window with --reset-interval: a delta export is only due
+/// once per window. `None` before the first window closed or without windows.
+pub fn window_end(state: &MetricsState) -> Option<f64> {
+    state
+        .last_window
+        .as_ref()
+        .map(|window| window.end_time)
+        .filter(|&end| end > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::tests::{mock_server, ok};
+    use crate::state::tests::{log_line, record, settings};
+    use std::time::Duration;
+
+    fn decode(body: &[u8]) -> Vec<Metric> {
+        let request = ExportMetricsServiceRequest::decode(body).unwrap();
+        request.resource_metrics[0].scope_metrics[0].metrics.clone()
+    }
+
+    fn histogram_points(metrics: &[Metric], name: &str) -> Vec<HistogramDataPoint> {
+        let metric = metrics.iter().find(|metric| metric.name == name).unwrap();
+        match &metric.data {
+            Some(metric::Data::Histogram(histogram)) => histogram.data_points.clone(),
+            _ => panic!("{} is not a histogram", name),
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_histograms_to_a_receiver() {
+        let (address, requests) = mock_server(ok("")).await;
+        let exporter = OtlpExporter::new(&format!("http://{}", address)).unwrap();
+
+        let mut settings = settings("unused");
+        settings.buckets = vec![0.1, 1.0];
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[log_line("/a", "200", "0.05"), log_line("/a", "200", "2")],
+        );
+
+        exporter
+            .export(&encode_metrics(&state, &state.metrics))
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/v1/metrics");
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("application/x-protobuf")
+        );
+
+        let metrics = decode(&requests[0].body);
+        let points = histogram_points(&metrics, "nginx_http_request_duration_seconds");
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].count, 2);
+        assert_eq!(points[0].explicit_bounds, [0.1, 1.0]);
+        // Per-bucket, not cumulative counts
+        assert_eq!(points[0].bucket_counts, [1, 0, 1]);
+        assert_eq!(points[0].start_time_unix_nano, nanos(state.start_time));
+    }
+
+    #[test]
+    fn windows_are_deltas_stamped_with_their_bounds() {
+        let mut settings = settings("unused");
+        settings.reset_interval = Some(Duration::from_secs(60));
+        let mut state = MetricsState::new(settings);
+        assert_eq!(window_end(&state), None);
+
+        record(&mut state, &[log_line("/a", "200", "0.05")]);
+        state.rotate_window().unwrap();
+
+        let window = state.last_window.as_ref().unwrap();
+        let (start, end) = (window.start_time, window.end_time);
+        assert_eq!(window_end(&state), Some(end));
+
+        let window = state.collect().unwrap();
+        let metrics = decode(&encode_metrics(&state, &window));
+        let metric = metrics
+            .iter()
+            .find(|metric| metric.name == "nginx_http_request_duration_seconds")
+            .unwrap();
+        let Some(metric::Data::Histogram(histogram)) = &metric.data else {
+            panic!("not a histogram");
+        };
+        assert_eq!(
+            histogram.aggregation_temporality,
+            AggregationTemporality::Delta as i32
+        );
+        assert_eq!(histogram.data_points[0].start_time_unix_nano, nanos(start));
+        assert_eq!(histogram.data_points[0].time_unix_nano, nanos(end));
+
+        // The next window starts where this one ended
+        state.rotate_window().unwrap();
+        assert_eq!(state.last_window.as_ref().unwrap().start_time, end);
+        assert!(window_end(&state) >= Some(end));
+    }
+}
@@ -0,0 +1,546 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+// Strings borrow from the line and are only allocated when they contain escapes,
+// fields the exporter doesn't use are skipped without being materialised
+
+#[derive(Debug, Deserialize)]
+pub struct NginxLogEntry<'a> {
+    #[serde(default, borrow)]
+    pub http: Option<HttpData<'a>>,
+    #[serde(default, borrow)]
+    pub nginx: Option<NginxData<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HttpData<'a> {
+    #[serde(borrow)]
+    pub response: ResponseData<'a>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct ResponseData<'a> {
+    // Kept unparsed, the status field name is configurable
+    #[serde(borrow)]
+    pub fields: HashMap<Cow<'a, str>, &'a RawValue>,
+}
+
+impl<'a> ResponseData<'a> {
+    /// Value of a field as a string or a number, `None` when missing or of another type.
+    pub fn field(&self, name: &str) -> Option<Cow<'a, str>> {
+        let raw = self.fields.get(name).copied()?.get();
+
+        match raw.as_bytes().first()? {
+            b'"' if !raw.contains('\\') => Some(Cow::Borrowed(&raw[1..raw.len() - 1])),
+            b'"' => serde_json::from_str::<String>(raw).ok().map(Cow::Owned),
+            b'-' | b'0'..=b'9' => Some(Cow::Borrowed(raw)),
+            _ => None,
+        }
+    }
+
+    /// Value of the first of `names` present, as a string or a number.
+    pub fn status(&self, names: &[String]) -> Option<Cow<'a, str>> {
+        names.iter().find_map(|name| self.field(name))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NginxData<'a> {
+    #[serde(borrow)]
+    pub access: AccessData<'a>,
+    #[serde(default, borrow)]
+    pub time: TimeData<'a>,
+    #[serde(default, borrow)]
+    pub http: Option<NginxHttpData<'a>>,
+    #[serde(default, borrow)]
+    pub bytes: Option<BytesData<'a>>,
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub pid: Option<Cow<'a, str>>,
+    #[serde(default, borrow)]
+    pub upstream: Option<UpstreamData<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccessData<'a> {
+    #[serde(borrow)]
+    pub method: Cow<'a, str>,
+    #[serde(borrow)]
+    pub url: Cow<'a, str>,
+    #[serde(borrow)]
+    pub host: Cow<'a, str>,
+    #[serde(
+        default,
+        borrow,
+        alias = "server_protocol",
+        deserialize_with = "optional_text"
+    )]
+    pub http_protocol: Option<Cow<'a, str>>,
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub scheme: Option<Cow<'a, str>>,
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub server_port: Option<Cow<'a, str>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NginxHttpData<'a> {
+    #[serde(
+        default,
+        borrow,
+        alias = "trace_id",
+        deserialize_with = "optional_text"
+    )]
+    pub http_x_b3_traceid: Option<Cow<'a, str>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BytesData<'a> {
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub request_length: Option<Cow<'a, str>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UpstreamData<'a> {
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub cache_status: Option<Cow<'a, str>>,
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub status: Option<Cow<'a, str>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TimeData<'a> {
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub request: Option<Cow<'a, str>>,
+    // $msec, Unix time the entry was logged at
+    #[serde(default, borrow, deserialize_with = "optional_text")]
+    pub msec: Option<Cow<'a, str>>,
+}
+
+// Serde only borrows a Cow that is the field's type itself, not one inside an Option
+#[derive(Deserialize)]
+struct Text<'a>(#[serde(borrow)] Cow<'a, str>);
+
+fn optional_text<'de: 'a, 'a, D>(deserializer: D) -> Result<Option<Cow<'a, str>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Text<'a>>::deserialize(deserializer)?.map(|text| text.0))
+}
+
+pub fn parse_line(line: &str) -> Result<NginxLogEntry<'_>, serde_json::Error> {
+    serde_json::from_str(line)
+}
+
+pub fn get_protocol_label(protocol: Option<&str>) -> &'static str {
+    match protocol {
+        Some("HTTP/1.0") => "HTTP/1.0",
+        Some("HTTP/1.1") => "HTTP/1.1",
+        Some("HTTP/2.0") => "HTTP/2.0",
+        Some("HTTP/3.0") => "HTTP/3.0",
+        _ => "other",
+    }
+}
+
+/// `$scheme` when logged, otherwise inferred from `$server_port`: 443 is taken
+/// for https and any other port for http.
+pub fn get_scheme_label(scheme: Option<&str>, server_port: Option<&str>) -> &'static str {
+    match (scheme, server_port) {
+        (Some("http"), _) => "http",
+        (Some("https"), _) => "https",
+        (Some(_), _) => "other",
+        (None, Some("443")) => "https",
+        (None, Some(_)) => "http",
+        (None, None) => "other",
+    }
+}
+
+/// Classifies `$sent_http_cache_control` by its first directive, ignoring any value
+/// (`max-age=60, public` -> `max-age`). A missing or empty header is `"none"`,
+/// directives outside RFC 9111 and its common extensions are `"other"`.
+pub fn get_cache_control_label(cache_control: Option<&str>) -> &'static str {
+    let directive = cache_control
+        .and_then(|value| value.split(',').next())
+        .map(|directive| directive.split('=').next().unwrap_or_default().trim())
+        .unwrap_or_default();
+
+    match directive.to_ascii_lowercase().as_str() {
+        "" | "-" => "none",
+        "no-cache" => "no-cache",
+        "no-store" => "no-store",
+        "max-age" => "max-age",
+        "s-maxage" => "s-maxage",
+        "private" => "private",
+        "public" => "public",
+        "must-revalidate" => "must-revalidate",
+        "proxy-revalidate" => "proxy-revalidate",
+        "no-transform" => "no-transform",
+        "immutable" => "immutable",
+        "stale-while-revalidate" => "stale-while-revalidate",
+        "stale-if-error" => "stale-if-error",
+        _ => "other",
+    }
+}
+
+/// Rewrites the `\xHH` escapes nginx writes for `"`, `\`, control and non-ASCII
+/// bytes when a log_format lacks `escape=json`, which JSON doesn't allow. Runs of
+/// escapes are decoded together so multi-byte characters stay whole. `None` when
+/// there was nothing to rewrite.
+pub fn repair_nginx_escapes(line: &str) -> Option<String> {
+    if !line.contains("\\x") {
+        return None;
+    }
+
+    let input = line.as_bytes();
+    let mut output = String::with_capacity(line.len());
+    let mut decoded = Vec::new();
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < input.len() {
+        if input[i] != b'\\' {
+            i += 1;
+            continue;
+        }
+
+        let byte = input
+            .get(i + 1..i + 4)
+            .filter(|escape| escape[0] == b'x' && escape[1..].iter().all(u8::is_ascii_hexdigit))
+            .and_then(|escape| std::str::from_utf8(&escape[1..]).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match byte {
+            Some(byte) => {
+                if i > copied {
+                    push_decoded(&mut output, &mut decoded);
+                    output.push_str(&line[copied..i]);
+                }
+                decoded.push(byte);
+                i += 4;
+                copied = i;
+            }
+            // A JSON escape, e.g. `\\x` is a backslash followed by x
+            None => i += 2,
+        }
+    }
+
+    if copied == 0 {
+        return None;
+    }
+
+    push_decoded(&mut output, &mut decoded);
+    output.push_str(&line[copied..]);
+
+    Some(output)
+}
+
+fn push_decoded(output: &mut String, bytes: &mut Vec<u8>) {
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            c if c.is_control() => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+
+    bytes.clear();
+}
+
+/// Truncates a path to its first `depth` segments (`/api/v1/users/123` -> `/api/v1`),
+/// dropping any query string. Shorter paths are returned as they are.
+pub fn truncate_path(path: &str, depth: usize) -> String {
+    let path = split_query(path).0;
+
+    match path.match_indices('/').nth(depth) {
+        Some((end, _)) if depth > 0 => path[..end].to_string(),
+        Some(_) => "/".to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// Splits a request URI into its path and query string, if any.
+pub fn split_query(uri: &str) -> (&str, Option<&str>) {
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
+}
+
+/// Parses an nginx timing field, summing the values of a list. Upstream timings hold
+/// one value per tried upstream, separated by `, ` (and ` : ` across internal
+/// redirects); `-` marks an upstream that didn't answer and is skipped. `None` when
+/// no part is a number.
+pub fn parse_timing_list(value: &str) -> Option<f64> {
+    let values: Vec<f64> = list_parts(value)
+        .filter_map(|part| part.parse::<f64>().ok())
+        .collect();
+
+    (!values.is_empty()).then(|| values.iter().sum())
+}
+
+/// The values of an nginx upstream list, in the order the upstreams were tried.
+/// Separators (`, `, ` : `) and `-` placeholders are dropped.
+pub fn list_parts(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(|c: char| c == ',' || c == ':' || c.is_whitespace())
+        .filter(|part| !part.is_empty() && *part != "-")
+}
+
+/// Classifies a status code, anything outside 100-599 (e.g. nginx's `000`) is `"other"`.
+/// With `separate_499`, nginx's 499 (client closed the request) is kept out of `4xx`.
+pub fn get_status_label(status_code: &str, separate_499: bool) -> &'static str {
+    match status_code.parse::<u16>() {
+        Ok(499) if separate_499 => "499",
+        Ok(100..=199) => "1xx",
+        Ok(200..=299) => "2xx",
+        Ok(300..=399) => "3xx",
+        Ok(400..=499) => "4xx",
+        Ok(500..=599) => "5xx",
+        _ => "other",
+    }
+}
+
+/// Classifies `$upstream_cache_status`. Requests that didn't go through a cache log an
+/// empty value or `-` and get `None`, unexpected values are `"other"`.
+pub fn get_cache_status_label(cache_status: &str) -> Option<&'static str> {
+    match cache_status {
+        "" | "-" => None,
+        "HIT" => Some("HIT"),
+        "MISS" => Some("MISS"),
+        "BYPASS" => Some("BYPASS"),
+        "EXPIRED" => Some("EXPIRED"),
+        "STALE" => Some("STALE"),
+        "UPDATING" => Some("UPDATING"),
+        "REVALIDATED" => Some("REVALIDATED"),
+        _ => Some("other"),
+    }
+}
+
+// Prepended by some Windows tools and shippers, serde_json rejects it
+const BOM: &str = "\u{feff}";
+
+/// Strips surrounding whitespace and any leading byte order mark from a line.
+pub fn trim_line(line: &str) -> &str {
+    line.trim_start_matches(|c: char| c.is_whitespace() || c == '\u{feff}')
+        .trim_end()
+}
+
+/// Skips whitespace and byte order marks from `from`, returning the first other position.
+pub fn skip_padding(buffer: &[u8], from: usize) -> usize {
+    let mut i = from;
+
+    while i < buffer.len() {
+        if buffer[i..].starts_with(BOM.as_bytes()) {
+            i += BOM.len();
+        } else if buffer[i].is_ascii_whitespace() {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+
+    i
+}
+
+/// Finds the start of the next line beginning with `{` or `[` at or after `from`,
+/// used to resynchronise the JSON stream after a syntax error.
+pub fn next_object_start(buffer: &[u8], from: usize) -> usize {
+    let mut line_start = true;
+    let mut i = from;
+
+    while i < buffer.len() {
+        match buffer[i] {
+            b'{' | b'[' if line_start => return i,
+            b'\n' => line_start = true,
+            b' ' | b'\t' | b'\r' => {}
+            _ if line_start && buffer[i..].starts_with(BOM.as_bytes()) => {
+                i += BOM.len();
+                continue;
+            }
+            _ => line_start = false,
+        }
+        i += 1;
+    }
+
+    buffer.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = r#"{"http":{"response":{"status_code":"200"}},"nginx":{"access":{"method":"GET","url":"/api/v1/users/123","host":"api.example.com"},"time":{"request":"0.042"}}}"#;
+
+    #[test]
+    fn parse_line_reads_required_fields() {
+        let entry = parse_line(LINE).unwrap();
+        let nginx = entry.nginx.unwrap();
+
+        assert_eq!(nginx.access.method, "GET");
+        assert_eq!(nginx.access.url, "/api/v1/users/123");
+        assert_eq!(nginx.access.host, "api.example.com");
+        assert_eq!(nginx.time.request.as_deref(), Some("0.042"));
+        assert_eq!(
+            entry
+                .http
+                .unwrap()
+                .response
+                .status(&["status_code".to_string()])
+                .as_deref(),
+            Some("200")
+        );
+    }
+
+    #[test]
+    fn parse_line_rejects_invalid_json() {
+        assert!(parse_line("{\"http\":").is_err());
+        assert!(parse_line("not json").is_err());
+    }
+
+    #[test]
+    fn parse_line_without_nginx_object() {
+        let entry = parse_line(r#"{"http":{"response":{"status_code":"200"}}}"#).unwrap();
+        assert!(entry.nginx.is_none());
+    }
+
+    #[test]
+    fn protocol_label_classifies_known_versions() {
+        assert_eq!(get_protocol_label(Some("HTTP/1.1")), "HTTP/1.1");
+        assert_eq!(get_protocol_label(Some("HTTP/2.0")), "HTTP/2.0");
+        assert_eq!(get_protocol_label(Some("SPDY/3")), "other");
+        assert_eq!(get_protocol_label(None), "other");
+
+        // $server_protocol is accepted under its own name too
+        let entry = parse_line(r#"{"nginx":{"access":{"method":"GET","url":"/","host":"h","server_protocol":"HTTP/2.0"}}}"#).unwrap();
+        assert_eq!(
+            entry.nginx.unwrap().access.http_protocol.as_deref(),
+            Some("HTTP/2.0")
+        );
+    }
+
+    #[test]
+    fn status_label_classifies_unknown_codes_as_other() {
+        assert_eq!(get_status_label("000", false), "other");
+        assert_eq!(get_status_label("600", false), "other");
+        assert_eq!(get_status_label("-", false), "other");
+        assert_eq!(get_status_label("499", false), "4xx");
+        assert_eq!(get_status_label("499", true), "499");
+        assert_eq!(get_status_label("404", true), "4xx");
+    }
+
+    #[test]
+    fn truncate_path_keeps_the_leading_segments() {
+        assert_eq!(truncate_path("/api/v1/users/123", 2), "/api/v1");
+        assert_eq!(truncate_path("/api/v1/users?id=1", 2), "/api/v1");
+        assert_eq!(truncate_path("/api", 2), "/api");
+        assert_eq!(truncate_path("/api/v1", 2), "/api/v1");
+        assert_eq!(truncate_path("/api?x=/a/b/c", 2), "/api");
+        assert_eq!(truncate_path("/", 2), "/");
+        assert_eq!(truncate_path("/api/v1", 0), "/");
+    }
+
+    #[test]
+    fn status_is_read_from_the_first_field_present() {
+        let names = ["status_code".to_string(), "status".to_string()];
+
+        let entry = parse_line(r#"{"http":{"response":{"status":404}}}"#).unwrap();
+        assert_eq!(
+            entry
+                .http
+                .as_ref()
+                .unwrap()
+                .response
+                .status(&names)
+                .as_deref(),
+            Some("404")
+        );
+
+        let entry =
+            parse_line(r#"{"http":{"response":{"status_code":"200","status":"500"}}}"#).unwrap();
+        assert_eq!(
+            entry
+                .http
+                .as_ref()
+                .unwrap()
+                .response
+                .status(&names)
+                .as_deref(),
+            Some("200")
+        );
+
+        let entry = parse_line(r#"{"http":{"response":{"code":"200"}}}"#).unwrap();
+        assert_eq!(entry.http.as_ref().unwrap().response.status(&names), None);
+    }
+
+    #[test]
+    fn timing_lists_are_summed() {
+        assert_eq!(parse_timing_list("0.1"), Some(0.1));
+        assert_eq!(parse_timing_list("0.1, 0.2"), Some(0.1 + 0.2));
+        assert_eq!(parse_timing_list("0.05 0.05"), Some(0.1));
+        assert_eq!(parse_timing_list("0.1, - : 0.3"), Some(0.1 + 0.3));
+        assert_eq!(parse_timing_list("-"), None);
+        assert_eq!(parse_timing_list(""), None);
+    }
+
+    #[test]
+    fn trim_line_strips_a_bom_and_whitespace() {
+        assert_eq!(trim_line("\u{feff}{\"a\":1}"), "{\"a\":1}");
+        assert_eq!(trim_line("  \t{\"a\":1} \r"), "{\"a\":1}");
+        assert_eq!(trim_line(" \u{feff}{\"a\":1}"), "{\"a\":1}");
+        assert_eq!(trim_line("\u{feff}"), "");
+    }
+
+    #[test]
+    fn scheme_label_falls_back_on_the_server_port() {
+        assert_eq!(get_scheme_label(Some("https"), Some("80")), "https");
+        assert_eq!(get_scheme_label(Some("gopher"), None), "other");
+        assert_eq!(get_scheme_label(None, Some("443")), "https");
+        assert_eq!(get_scheme_label(None, Some("8080")), "http");
+        assert_eq!(get_scheme_label(None, None), "other");
+    }
+
+    #[test]
+    fn parse_line_borrows_unless_a_value_is_escaped() {
+        let entry = parse_line(LINE).unwrap();
+        let nginx = entry.nginx.unwrap();
+        assert!(matches!(nginx.access.url, Cow::Borrowed(_)));
+        assert!(matches!(nginx.time.request, Some(Cow::Borrowed("0.042"))));
+
+        let escaped = LINE.replace("/api/v1/users/123", r#"/api/v1/üsers/\"x\""#);
+        let entry = parse_line(&escaped).unwrap();
+        let nginx = entry.nginx.unwrap();
+        assert!(matches!(nginx.access.url, Cow::Owned(_)));
+        assert_eq!(nginx.access.url, "/api/v1/üsers/\"x\"");
+    }
+
+    #[test]
+    fn cache_control_label_is_the_first_directive() {
+        assert_eq!(get_cache_control_label(Some("max-age=60")), "max-age");
+        assert_eq!(
+            get_cache_control_label(Some("max-age=60, public")),
+            "max-age"
+        );
+        assert_eq!(get_cache_control_label(Some("No-Store")), "no-store");
+        assert_eq!(get_cache_control_label(Some("x-custom=1")), "other");
+        assert_eq!(get_cache_control_label(Some("-")), "none");
+        assert_eq!(get_cache_control_label(None), "none");
+    }
+
+    #[test]
+    fn nginx_escapes_are_repaired_into_json() {
+        let line = r#"{"url":"/a\x22b\x5Cc","agent":"caf\xC3\xA9\x0A"}"#;
+        assert!(serde_json::from_str::<serde_json::Value>(line).is_err());
+
+        let repaired = repair_nginx_escapes(line).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&repaired).unwrap();
+        assert_eq!(value["url"], "/a\"b\\c");
+        // A multi-byte character split over escapes stays whole
+        assert_eq!(value["agent"], "café\n");
+
+        // Nothing to rewrite: JSON escapes, including an escaped backslash before x
+        assert_eq!(repair_nginx_escapes(r#"{"url":"/a\\x22"}"#), None);
+        assert_eq!(repair_nginx_escapes(r#"{"url":"/a"}"#), None);
+    }
+}
@@ -0,0 +1,373 @@
+//! Prometheus protobuf exposition format (`io.prometheus.client` data model).
+//!
+//! Only the messages the exporter emits are declared here, with the field tags
+//! from the upstream `metrics.proto` so any Prometheus decoder can read them.
+
+use crate::metrics::{
+    parse_series, split_sample, Exemplar, MetricDescriptions, MetricLabels, Observations,
+};
+use prost::Message;
+use std::collections::HashMap;
+
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPair {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub value: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricType {
+    Counter = 0,
+    Gauge = 1,
+    Summary = 2,
+    Untyped = 3,
+    Histogram = 4,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoExemplar {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(double, optional, tag = "2")]
+    pub value: Option<f64>,
+    #[prost(message, optional, tag = "3")]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Gauge {
+    #[prost(double, optional, tag = "1")]
+    pub value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Counter {
+    #[prost(double, optional, tag = "1")]
+    pub value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Untyped {
+    #[prost(double, optional, tag = "1")]
+    pub value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Bucket {
+    #[prost(uint64, optional, tag = "1")]
+    pub cumulative_count: Option<u64>,
+    #[prost(double, optional, tag = "2")]
+    pub upper_bound: Option<f64>,
+    #[prost(message, optional, tag = "3")]
+    pub exemplar: Option<ProtoExemplar>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Histogram {
+    #[prost(uint64, optional, tag = "1")]
+    pub sample_count: Option<u64>,
+    #[prost(double, optional, tag = "2")]
+    pub sample_sum: Option<f64>,
+    #[prost(message, repeated, tag = "3")]
+    pub bucket: Vec<Bucket>,
+}
+
+// Quantiles (tag 3) are never sent
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(uint64, optional, tag = "1")]
+    pub sample_count: Option<u64>,
+    #[prost(double, optional, tag = "2")]
+    pub sample_sum: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    pub gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    pub counter: Option<Counter>,
+    #[prost(message, optional, tag = "4")]
+    pub summary: Option<Summary>,
+    #[prost(message, optional, tag = "5")]
+    pub untyped: Option<Untyped>,
+    #[prost(message, optional, tag = "7")]
+    pub histogram: Option<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricFamily {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub help: Option<String>,
+    #[prost(enumeration = "MetricType", optional, tag = "3")]
+    pub r#type: Option<i32>,
+    #[prost(message, repeated, tag = "4")]
+    pub metric: Vec<Metric>,
+}
+
+fn label_pair(name: &str, value: &str) -> LabelPair {
+    LabelPair {
+        name: Some(name.to_string()),
+        value: Some(value.to_string()),
+    }
+}
+
+fn to_proto_exemplar(exemplar: &Exemplar) -> ProtoExemplar {
+    ProtoExemplar {
+        label: vec![label_pair("trace_id", &exemplar.trace_id)],
+        value: Some(exemplar.value),
+        timestamp: Some(Timestamp {
+            seconds: exemplar.timestamp.trunc() as i64,
+            nanos: (exemplar.timestamp.fract() * 1e9) as i32,
+        }),
+    }
+}
+
+/// Encodes one histogram as a length-delimited `MetricFamily`. Without buckets
+/// (`--sum-count-only`) it is encoded as a summary with only a sum and a count.
+pub fn encode_histogram(
+    name: &str,
+    help: &str,
+    metrics: &HashMap<MetricLabels, Observations>,
+    exemplars: Option<&HashMap<MetricLabels, Vec<Option<Exemplar>>>>,
+    buckets: Option<&[f64]>,
+    descriptions: &MetricDescriptions,
+    static_labels: &[(String, String)],
+) -> Vec<u8> {
+    let metric = metrics
+        .iter()
+        .map(|(labels, durations)| {
+            let bucket_counts =
+                buckets.and_then(|buckets| Some((buckets, durations.bucket_counts(buckets)?)));
+            let slots = exemplars.and_then(|exemplars| exemplars.get(labels));
+
+            let histogram = bucket_counts.map(|(buckets, bucket_counts)| Histogram {
+                sample_count: Some(durations.count() as u64),
+                sample_sum: Some(durations.sum()),
+                bucket: buckets
+                    .iter()
+                    .zip(bucket_counts)
+                    .enumerate()
+                    .map(|(i, (&upper_bound, count))| Bucket {
+                        cumulative_count: Some(count as u64),
+                        upper_bound: Some(upper_bound),
+                        exemplar: slots
+                            .and_then(|slots| slots.get(i))
+                            .and_then(Option::as_ref)
+                            .map(to_proto_exemplar),
+                    })
+                    .collect(),
+            });
+            let summary = histogram.is_none().then(|| Summary {
+                sample_count: Some(durations.count() as u64),
+                sample_sum: Some(durations.sum()),
+            });
+
+            Metric {
+                label: labels
+                    .pairs()
+                    .into_iter()
+                    .map(|(name, value)| label_pair(name, value))
+                    .chain(
+                        static_labels
+                            .iter()
+                            .map(|(name, value)| label_pair(name, value)),
+                    )
+                    .collect(),
+                summary,
+                histogram,
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let family = MetricFamily {
+        name: Some(name.to_string()),
+        help: Some(descriptions.help(name, help).to_string()),
+        r#type: Some(match buckets {
+            Some(_) => MetricType::Histogram as i32,
+            None => MetricType::Summary as i32,
+        }),
+        metric,
+    };
+
+    family.encode_length_delimited_to_vec()
+}
+
+/// Encodes the counter and gauge families of a text exposition, as rendered by
+/// `render_counters_text`, as length-delimited `MetricFamily` messages in their order.
+pub fn encode_text_families(text: &str) -> Vec<u8> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (keyword, name) = (parts.next(), parts.next().unwrap_or_default());
+            let rest = parts.next().unwrap_or_default().to_string();
+
+            if families.last().is_none_or(|family| family.name() != name) {
+                families.push(MetricFamily {
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                });
+            }
+
+            let family = families.last_mut().unwrap();
+            match keyword {
+                Some("HELP") => family.help = Some(rest),
+                Some("TYPE") if rest == "counter" => family.set_type(MetricType::Counter),
+                Some("TYPE") if rest == "gauge" => family.set_type(MetricType::Gauge),
+                Some("TYPE") => family.set_type(MetricType::Untyped),
+                _ => {}
+            }
+            continue;
+        }
+
+        let (Some(family), Some((series, value))) = (families.last_mut(), split_sample(line))
+        else {
+            continue;
+        };
+
+        let (_, pairs) = parse_series(series);
+        let mut metric = Metric {
+            label: pairs
+                .iter()
+                .map(|(name, value)| label_pair(name, value))
+                .collect(),
+            ..Default::default()
+        };
+
+        match family.r#type() {
+            MetricType::Counter => metric.counter = Some(Counter { value: Some(value) }),
+            MetricType::Gauge => metric.gauge = Some(Gauge { value: Some(value) }),
+            _ => metric.untyped = Some(Untyped { value: Some(value) }),
+        }
+
+        family.metric.push(metric);
+    }
+
+    families
+        .iter()
+        .filter(|family| !family.metric.is_empty())
+        .flat_map(|family| family.encode_length_delimited_to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{render_counters_text, render_text};
+    use crate::state::tests::{log_line, record, settings};
+    use crate::state::MetricsState;
+
+    fn decode(mut body: &[u8]) -> Vec<MetricFamily> {
+        let mut families = Vec::new();
+        while !body.is_empty() {
+            families.push(MetricFamily::decode_length_delimited(&mut body).unwrap());
+        }
+        families
+    }
+
+    #[test]
+    fn histogram_round_trip() {
+        let mut state = MetricsState::new(settings("unused"));
+        record(
+            &mut state,
+            &[log_line("/a", "200", "0.003"), log_line("/a", "200", "3")],
+        );
+
+        let body = encode_histogram(
+            "nginx_http_request_duration_seconds",
+            "Request duration in seconds",
+            &state.metrics,
+            None,
+            Some(&[0.005, 1.0]),
+            &MetricDescriptions::default(),
+            &[("env".to_string(), "prod".to_string())],
+        );
+        let families = decode(&body);
+
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].r#type(), MetricType::Histogram);
+
+        let metric = &families[0].metric[0];
+        assert!(metric.label.contains(&label_pair("env", "prod")));
+        assert!(metric.label.contains(&label_pair("path", "/a")));
+
+        let histogram = metric.histogram.as_ref().unwrap();
+        assert_eq!(histogram.sample_count, Some(2));
+        assert_eq!(histogram.sample_sum, Some(3.003));
+        assert_eq!(
+            histogram
+                .bucket
+                .iter()
+                .map(|bucket| bucket.cumulative_count.unwrap())
+                .collect::<Vec<_>>(),
+            [1, 1]
+        );
+    }
+
+    #[test]
+    fn counters_and_gauges_are_encoded() {
+        let mut state = MetricsState::new(settings("unused"));
+        record(&mut state, &[log_line("/a", "200", "0.1")]);
+
+        let families = decode(&encode_text_families(&render_counters_text(&state, 1)));
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.name() == name)
+                .unwrap_or_else(|| panic!("{} missing", name))
+        };
+
+        let requests = family("nginx_http_requests_total");
+        assert_eq!(requests.r#type(), MetricType::Counter);
+        assert_eq!(
+            requests.metric[0].counter.as_ref().unwrap().value,
+            Some(1.0)
+        );
+        assert!(requests.metric[0]
+            .label
+            .contains(&label_pair("host", "example.com")));
+
+        let by_host = family("nginx_http_requests_by_host_total");
+        assert_eq!(by_host.metric[0].counter.as_ref().unwrap().value, Some(1.0));
+
+        let watched = family("nginx_exporter_files_watched");
+        assert_eq!(watched.r#type(), MetricType::Gauge);
+        assert_eq!(watched.metric[0].gauge.as_ref().unwrap().value, Some(0.0));
+
+        // Every family of the text exposition with samples, besides the histograms
+        let text = render_text(&state, &state.metrics, false, false);
+        let text_families = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .filter(|line| !line.ends_with(" histogram"))
+            .filter_map(|line| line.split(' ').next())
+            .filter(|name| {
+                text.lines().any(|line| {
+                    split_sample(line).is_some_and(|(series, _)| parse_series(series).0 == *name)
+                })
+            })
+            .count();
+        assert_eq!(families.len(), text_families);
+    }
+}
@@ -0,0 +1,400 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Splits an `http://host[:port][/base]` URL into `host:port` and the base path
+/// without a trailing slash.
+pub fn split_http_url(url: &str) -> Result<(String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("URL must start with http://, got '{}'", url))?;
+
+    let (authority, base) = rest.split_once('/').unwrap_or((rest, ""));
+
+    if authority.is_empty() {
+        return Err(format!("URL without host: '{}'", url));
+    }
+
+    let host = match authority.contains(':') {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+    };
+
+    let path = format!("/{}", base.trim_end_matches('/'))
+        .trim_end_matches('/')
+        .to_string();
+
+    Ok((host, path))
+}
+
+/// Status, headers and (dechunked) body of a response to `request`.
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends one plain HTTP/1.1 request on a fresh connection and returns the response body,
+/// failing unless the answer is 2xx. `headers` come in addition to Host, Content-Length
+/// and Connection.
+pub async fn send_request(
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Vec<u8>, String> {
+    let response = request(host, method, path, headers, body).await?;
+
+    if !(200..300).contains(&response.status) {
+        return Err(format!(
+            "{} answered {} {} with status {}",
+            host, method, path, response.status
+        ));
+    }
+
+    Ok(response.body)
+}
+
+/// Like `send_request`, but returns the response whatever its status.
+pub async fn request(
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<HttpResponse, String> {
+    let mut stream = TcpStream::connect(host)
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", host, e))?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    );
+
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|e| format!("Failed to send {} {}: {}", method, path, e))?;
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| format!("Failed to read response from {}: {}", host, e))?;
+
+    let (head, body) = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => (&response[..], &[][..]),
+    };
+
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            format!(
+                "{} answered {} {} with '{}'",
+                host, method, path, status_line
+            )
+        })?;
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+
+    response.body = match response.header("transfer-encoding") == Some("chunked") {
+        true => dechunk(body).ok_or_else(|| format!("Malformed chunked response from {}", host))?,
+        false => body.to_vec(),
+    };
+
+    Ok(response)
+}
+
+// Joins the chunks of a `Transfer-Encoding: chunked` body, trailers are ignored
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        // Chunk extensions follow the size after a `;`
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+
+        if size == 0 {
+            return Some(output);
+        }
+
+        let chunk = body.get(line_end + 2..line_end + 2 + size)?;
+        output.extend_from_slice(chunk);
+        body = body.get(line_end + 2 + size + 2..)?;
+    }
+}
+
+/// Client for one Pushgateway grouping key, i.e. `<url>/metrics/job/<job>[/instance/<instance>]`.
+pub struct Pushgateway {
+    host: String,
+    path: String,
+}
+
+impl Pushgateway {
+    pub fn new(url: &str, job: &str, instance: Option<&str>) -> Result<Self, String> {
+        let (host, mut path) =
+            split_http_url(url).map_err(|e| format!("Invalid Pushgateway URL: {}", e))?;
+        path.push_str(&format!("/metrics/job/{}", job));
+
+        if let Some(instance) = instance {
+            path.push_str(&format!("/instance/{}", instance));
+        }
+
+        Ok(Self { host, path })
+    }
+
+    /// Replaces the pushed metrics that share a name with the ones in `body`.
+    pub async fn push(&self, body: &str) -> Result<(), String> {
+        self.send("POST", body).await
+    }
+
+    /// Deletes the whole group, so a stopped exporter doesn't leave stale series behind.
+    pub async fn delete(&self) -> Result<(), String> {
+        self.send("DELETE", "").await
+    }
+
+    async fn send(&self, method: &str, body: &str) -> Result<(), String> {
+        send_request(
+            &self.host,
+            method,
+            &self.path,
+            &[("Content-Type", "text/plain; version=0.0.4")],
+            body.as_bytes(),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// A request received by `mock_server`.
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockRequest {
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl MockRequest {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    type Handler = dyn Fn(&MockRequest) -> (u16, Vec<(String, String)>, Vec<u8>) + Send + Sync;
+
+    /// A plain HTTP/1.1 server on a free local port answering every request with
+    /// `handler`, one request per connection. Returns `host:port` and the requests seen.
+    pub(crate) async fn mock_server(
+        handler: impl Fn(&MockRequest) -> (u16, Vec<(String, String)>, Vec<u8>) + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<MockRequest>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let seen = Arc::clone(&seen);
+                let handler = Arc::clone(&handler);
+
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let (status, headers, body) = handler(&request);
+                    seen.lock().unwrap().push(request);
+
+                    let mut response = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        status,
+                        body.len()
+                    );
+                    for (name, value) in headers {
+                        response.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    response.push_str("\r\n");
+
+                    let mut response = response.into_bytes();
+                    response.extend(body);
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        (address, requests)
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> Option<MockRequest> {
+        let mut data = Vec::new();
+        let mut buffer = [0; 4096];
+
+        let head_end = loop {
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i;
+            }
+            let read = stream.read(&mut buffer).await.ok()?;
+            if read == 0 {
+                return None;
+            }
+            data.extend_from_slice(&buffer[..read]);
+        };
+
+        let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+        let mut lines = head.lines();
+        let mut request_line = lines.next()?.split_whitespace();
+        let (method, path) = (request_line.next()?, request_line.next()?);
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let mut body = data[head_end + 4..].to_vec();
+        while body.len() < length {
+            let read = stream.read(&mut buffer).await.ok()?;
+            if read == 0 {
+                break;
+            }
+            body.extend_from_slice(&buffer[..read]);
+        }
+
+        Some(MockRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            body,
+        })
+    }
+
+    /// A handler answering 200 with `body`.
+    pub(crate) fn ok(
+        body: &str,
+    ) -> impl Fn(&MockRequest) -> (u16, Vec<(String, String)>, Vec<u8>) + Send + Sync + 'static
+    {
+        let body = body.as_bytes().to_vec();
+        move |_| (200, Vec::new(), body.clone())
+    }
+
+    #[tokio::test]
+    async fn send_request_fails_unless_2xx() {
+        let (address, requests) = mock_server(ok("fine")).await;
+
+        let body = send_request(&address, "POST", "/ok", &[("X-Test", "1")], b"data")
+            .await
+            .unwrap();
+        assert_eq!(body, b"fine");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/ok")
+        );
+        assert_eq!(request.header("x-test"), Some("1"));
+        assert_eq!(request.body, b"data");
+
+        let (address, _) = mock_server(|_| (500, Vec::new(), Vec::new())).await;
+        let error = send_request(&address, "GET", "/broken", &[], &[])
+            .await
+            .unwrap_err();
+        assert!(error.contains("500"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn pushes_and_deletes_the_group() {
+        let (address, requests) = mock_server(ok("")).await;
+        let gateway =
+            Pushgateway::new(&format!("http://{}/base", address), "nginx", Some("web-1")).unwrap();
+
+        gateway.push("nginx_exporter_up 1\n").await.unwrap();
+        gateway.delete().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<_> = requests
+            .iter()
+            .map(|request| (request.method.as_str(), request.path.as_str()))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                ("POST", "/base/metrics/job/nginx/instance/web-1"),
+                ("DELETE", "/base/metrics/job/nginx/instance/web-1"),
+            ]
+        );
+        assert_eq!(requests[0].body, b"nginx_exporter_up 1\n");
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("text/plain; version=0.0.4")
+        );
+    }
+
+    #[test]
+    fn instance_is_optional() {
+        assert_eq!(
+            Pushgateway::new("http://gateway:9091", "nginx", None)
+                .unwrap()
+                .path,
+            "/metrics/job/nginx"
+        );
+        assert!(Pushgateway::new("https://gateway", "nginx", None).is_err());
+    }
+}
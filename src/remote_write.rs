@@ -0,0 +1,286 @@
+//! Prometheus remote write 1.0 (`prometheus.WriteRequest`).
+//!
+//! Only the messages the exporter sends are declared here, with the field tags
+//! from the upstream `remote.proto` and `types.proto`.
+
+use crate::metrics::{
+    duration_metric, request_size_metric, MetricLabels, Observations, METRICS_PREFIX,
+};
+use crate::push::{send_request, split_http_url};
+use crate::state::MetricsState;
+use prost::Message;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    // Milliseconds since the Unix epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// Sends snappy-compressed write requests to a remote write receiver.
+pub struct RemoteWriter {
+    host: String,
+    path: String,
+}
+
+impl RemoteWriter {
+    pub fn new(url: &str) -> Result<Self, String> {
+        let (host, path) =
+            split_http_url(url).map_err(|e| format!("Invalid remote write URL: {}", e))?;
+
+        Ok(Self { host, path })
+    }
+
+    pub async fn write(&self, body: &[u8]) -> Result<(), String> {
+        send_request(
+            &self.host,
+            "POST",
+            &self.path,
+            &[
+                ("Content-Type", "application/x-protobuf"),
+                ("Content-Encoding", "snappy"),
+                ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+            ],
+            body,
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+// Receivers expect the labels of a series sorted by name
+fn series(name: String, labels: &[(&str, &str)], value: f64, timestamp: i64) -> TimeSeries {
+    let mut labels: Vec<Label> = std::iter::once(("__name__", name.as_str()))
+        .chain(labels.iter().copied())
+        .map(|(name, value)| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+        .collect();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    TimeSeries {
+        labels,
+        samples: vec![Sample { value, timestamp }],
+    }
+}
+
+/// Classic histogram series (`_bucket` with cumulative counts, `_sum`, `_count`),
+/// all sampled at the same timestamp. Without buckets only `_sum` and `_count` are sent.
+fn histogram(
+    name: &str,
+    metrics: &HashMap<MetricLabels, Observations>,
+    buckets: Option<&[f64]>,
+    static_labels: &[(String, String)],
+    timestamp: i64,
+) -> Vec<TimeSeries> {
+    let mut output = Vec::new();
+
+    for (labels, values) in metrics {
+        let mut pairs = labels.pairs();
+        pairs.extend(
+            static_labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+        let bucket_counts =
+            buckets.and_then(|buckets| Some((buckets, values.bucket_counts(buckets)?)));
+
+        if let Some((buckets, counts)) = bucket_counts {
+            let bounds = buckets.iter().map(|bucket| bucket.to_string());
+
+            for (bound, count) in bounds
+                .chain(["+Inf".to_string()])
+                .zip(counts.into_iter().chain([values.count()]))
+            {
+                let mut bucket_pairs = pairs.clone();
+                bucket_pairs.push(("le", &bound));
+                output.push(series(
+                    format!("{}_bucket", name),
+                    &bucket_pairs,
+                    count as f64,
+                    timestamp,
+                ));
+            }
+        }
+
+        output.push(series(
+            format!("{}_sum", name),
+            &pairs,
+            values.sum(),
+            timestamp,
+        ));
+        output.push(series(
+            format!("{}_count", name),
+            &pairs,
+            values.count() as f64,
+            timestamp,
+        ));
+    }
+
+    output
+}
+
+/// Encodes the request histograms as a snappy-compressed WriteRequest.
+pub fn encode_metrics(
+    state: &MetricsState,
+    metrics: &HashMap<MetricLabels, Observations>,
+) -> Result<Vec<u8>, String> {
+    let duration_name = duration_metric(METRICS_PREFIX);
+    let size_name = request_size_metric(METRICS_PREFIX);
+    let static_labels = &state.settings.static_labels;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+
+    let histograms = [
+        (&duration_name, metrics),
+        (&size_name, state.current_request_sizes()),
+    ];
+
+    let timeseries = histograms
+        .into_iter()
+        .filter(|(name, _)| state.settings.metric_enabled(name))
+        .flat_map(|(name, series)| {
+            histogram(
+                name,
+                series,
+                state.settings.histogram_buckets(name),
+                static_labels,
+                timestamp,
+            )
+        })
+        .collect();
+
+    let request = WriteRequest { timeseries }.encode_to_vec();
+
+    // Remote write uses the block format, not the framed stream format
+    snap::raw::Encoder::new()
+        .compress_vec(&request)
+        .map_err(|e| format!("Failed to compress write request: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::tests::{mock_server, ok};
+    use crate::state::tests::{log_line, record, settings};
+
+    fn label<'a>(series: &'a TimeSeries, name: &str) -> Option<&'a str> {
+        series
+            .labels
+            .iter()
+            .find(|label| label.name == name)
+            .map(|label| label.value.as_str())
+    }
+
+    #[tokio::test]
+    async fn receiver_decodes_the_histogram_series() {
+        let (address, requests) = mock_server(ok("")).await;
+        let writer = RemoteWriter::new(&format!("http://{}/api/v1/write", address)).unwrap();
+
+        let mut settings = settings("unused");
+        settings.buckets = vec![0.1, 1.0];
+        settings.static_labels = vec![("env".to_string(), "prod".to_string())];
+        let mut state = MetricsState::new(settings);
+        record(
+            &mut state,
+            &[log_line("/a", "200", "0.05"), log_line("/a", "200", "2")],
+        );
+
+        writer
+            .write(&encode_metrics(&state, &state.metrics).unwrap())
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/api/v1/write");
+        assert_eq!(requests[0].header("content-encoding"), Some("snappy"));
+
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&requests[0].body)
+            .unwrap();
+        let request = WriteRequest::decode(body.as_slice()).unwrap();
+
+        let duration: Vec<_> = request
+            .timeseries
+            .iter()
+            .filter(|series| {
+                label(series, "__name__")
+                    .is_some_and(|name| name.starts_with("nginx_http_request_duration_seconds"))
+            })
+            .collect();
+        let samples: Vec<_> = duration
+            .iter()
+            .map(|series| {
+                (
+                    label(series, "__name__").unwrap(),
+                    label(series, "le"),
+                    series.samples[0].value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (
+                    "nginx_http_request_duration_seconds_bucket",
+                    Some("0.1"),
+                    1.0
+                ),
+                ("nginx_http_request_duration_seconds_bucket", Some("1"), 1.0),
+                (
+                    "nginx_http_request_duration_seconds_bucket",
+                    Some("+Inf"),
+                    2.0
+                ),
+                ("nginx_http_request_duration_seconds_sum", None, 2.05),
+                ("nginx_http_request_duration_seconds_count", None, 2.0),
+            ]
+        );
+
+        // One timestamp for the whole histogram, labels sorted by name
+        let timestamp = duration[0].samples[0].timestamp;
+        assert!(duration
+            .iter()
+            .all(|series| series.samples[0].timestamp == timestamp));
+        let names: Vec<_> = duration[0]
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert_eq!(label(duration[0], "env"), Some("prod"));
+    }
+}
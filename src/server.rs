@@ -0,0 +1,940 @@
+use crate::metrics::{
+    duration_metric, render_counters_text, render_json_lines, render_text, request_size_metric,
+    METRICS_PREFIX, REQUEST_SIZE_HELP,
+};
+use crate::protobuf;
+use crate::state::{file_label, MetricsState};
+use axum::extract::{Query, Request};
+use axum::http::{header, HeaderMap};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse};
+use axum::{http::HeaderValue, http::StatusCode, response::Response, routing::get, Router};
+use log::error;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Some scrapers check the version parameter, axum's String default doesn't carry it
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+fn accepts_openmetrics(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/openmetrics-text"))
+}
+
+fn accepts_protobuf(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.contains("application/vnd.google.protobuf")
+                && value.contains("io.prometheus.client.MetricFamily")
+        })
+}
+
+fn read_failed(e: String) -> Response {
+    error!("Error reading log entries: {}", e);
+
+    // JSON rather than text, so nothing mistakes the body for an exposition
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        axum::Json(serde_json::json!({"error": "read_failed", "message": e})),
+    )
+        .into_response()
+}
+
+// Prometheus marks the target down for the scrape instead of ingesting startup transients
+fn warming_up() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        axum::Json(
+            serde_json::json!({"error": "warming_up", "message": "--warmup has not elapsed yet"}),
+        ),
+    )
+        .into_response()
+}
+
+async fn metrics_handler(state: Arc<Mutex<MetricsState>>, headers: HeaderMap) -> Response {
+    let mut state = state.lock().unwrap();
+
+    // Exemplars are only valid in the OpenMetrics exposition format
+    // Federated sources are merged in the text format only
+    let federating = !state.federated.is_empty();
+    let openmetrics = state.settings.with_exemplars && !federating && accepts_openmetrics(&headers);
+
+    let metrics_map = match state.collect() {
+        Ok(m) => m,
+        Err(e) => return read_failed(e),
+    };
+
+    // Files are still read during the warm-up, so the backlog is caught up by its end
+    if state.warming_up() {
+        return warming_up();
+    }
+
+    state.check_exposition_size(metrics_map.len());
+
+    let exemplars_map = state.current_exemplars();
+
+    if !federating && accepts_protobuf(&headers) {
+        let duration_name = duration_metric(METRICS_PREFIX);
+        let size_name = request_size_metric(METRICS_PREFIX);
+
+        let mut body = Vec::new();
+        if state.settings.metric_enabled(&duration_name) {
+            body.extend(protobuf::encode_histogram(
+                &duration_name,
+                "Request duration in seconds",
+                &metrics_map,
+                Some(exemplars_map).filter(|_| state.settings.with_exemplars),
+                state.settings.histogram_buckets(&duration_name),
+                &state.settings.descriptions,
+                &state.settings.static_labels,
+            ));
+        }
+        if state.settings.metric_enabled(&size_name) {
+            body.extend(protobuf::encode_histogram(
+                &size_name,
+                REQUEST_SIZE_HELP,
+                state.current_request_sizes(),
+                None,
+                state.settings.histogram_buckets(&size_name),
+                &state.settings.descriptions,
+                &state.settings.static_labels,
+            ));
+        }
+
+        // Counters and gauges are encoded from their text rendering
+        body.extend(protobuf::encode_text_families(&render_counters_text(
+            &state,
+            metrics_map.len(),
+        )));
+
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, protobuf::CONTENT_TYPE)],
+            body,
+        )
+            .into_response();
+    }
+
+    let output = render_text(&state, &metrics_map, openmetrics, true);
+
+    if openmetrics {
+        return (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)],
+            format!("{}# EOF\n", output),
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, TEXT_CONTENT_TYPE)],
+        output,
+    )
+        .into_response()
+}
+
+async fn metrics_json_handler(state: Arc<Mutex<MetricsState>>) -> Response {
+    let mut state = state.lock().unwrap();
+
+    let metrics_map = match state.collect() {
+        Ok(m) => m,
+        Err(e) => return read_failed(e),
+    };
+
+    state.check_exposition_size(metrics_map.len());
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        render_json_lines(&state, &metrics_map),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct TailQuery {
+    file: String,
+    lines: Option<usize>,
+}
+
+async fn tail_handler(
+    state: Arc<Mutex<MetricsState>>,
+    token: Arc<str>,
+    headers: HeaderMap,
+    query: TailQuery,
+) -> Response {
+    // Log entries may carry client data, so they are only served with the token
+    if !authorized(&headers, &token) {
+        return unauthorized();
+    }
+
+    let state = state.lock().unwrap();
+
+    // Accept either the watched path or just its basename
+    let recent = state.log_files.iter().find_map(|(path, meta)| {
+        (path.as_os_str() == query.file.as_str() || file_label(path) == query.file)
+            .then_some(&meta.recent)
+    });
+
+    // HTTP sources are addressed by their URL
+    let recent = recent.or_else(|| {
+        state
+            .http_sources
+            .iter()
+            .find(|tail| tail.url == query.file)
+            .map(|tail| &tail.recent)
+    });
+
+    // The journal source is addressed by its unit name
+    #[cfg(feature = "journald")]
+    let recent = recent.or_else(|| {
+        state
+            .journal
+            .as_ref()
+            .filter(|journal| journal.unit == query.file)
+            .map(|journal| &journal.recent)
+    });
+
+    let Some(recent) = recent else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("File {} is not watched\n", query.file),
+        )
+            .into_response();
+    };
+
+    let lines = query.lines.unwrap_or(20).min(recent.len());
+    let entries: Vec<_> = recent.iter().skip(recent.len() - lines).collect();
+
+    axum::Json(entries).into_response()
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == token)
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "Unauthorized\n",
+    )
+        .into_response()
+}
+
+async fn last_error_handler(
+    state: Arc<Mutex<MetricsState>>,
+    token: Arc<str>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &token) {
+        return unauthorized();
+    }
+
+    // null until an entry failed to parse
+    let state = state.lock().unwrap();
+    axum::Json(state.last_parse_error.clone()).into_response()
+}
+
+#[derive(Deserialize)]
+struct CardinalityQuery {
+    top: Option<usize>,
+}
+
+async fn cardinality_handler(
+    state: Arc<Mutex<MetricsState>>,
+    token: Arc<str>,
+    headers: HeaderMap,
+    query: CardinalityQuery,
+) -> Response {
+    if !authorized(&headers, &token) {
+        return unauthorized();
+    }
+
+    // Series as of the last scrape, nothing is read from the logs
+    let state = state.lock().unwrap();
+    axum::Json(state.cardinality(query.top.unwrap_or(10))).into_response()
+}
+
+async fn config_handler(
+    state: Arc<Mutex<MetricsState>>,
+    token: Arc<str>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&headers, &token) {
+        return unauthorized();
+    }
+
+    let state = state.lock().unwrap();
+    axum::Json(state.settings.to_json()).into_response()
+}
+
+async fn landing_handler(endpoints: Arc<Vec<String>>) -> Response {
+    // Endpoints with placeholders are listed, not linked
+    let items: String = endpoints
+        .iter()
+        .map(|endpoint| match endpoint.contains('<') {
+            true => format!(
+                "<li><code>{}</code></li>",
+                endpoint
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+            ),
+            false => format!("<li><a href=\"{0}\">{0}</a></li>", endpoint),
+        })
+        .collect();
+
+    Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Nginx Prometheus Exporter</title></head>\n<body>\n<h1>Nginx Prometheus Exporter</h1>\n<p>Version {}</p>\n<ul>{}</ul>\n</body>\n</html>\n",
+        env!("CARGO_PKG_VERSION"),
+        items
+    ))
+    .into_response()
+}
+
+async fn not_found_handler(endpoints: Arc<Vec<String>>) -> Response {
+    let listing: String = endpoints
+        .iter()
+        .map(|endpoint| format!("  {}\n", endpoint))
+        .collect();
+
+    (
+        StatusCode::NOT_FOUND,
+        format!("Not found. Available endpoints:\n{}", listing),
+    )
+        .into_response()
+}
+
+/// Token bucket refilled at `rate` tokens per second, holding at most one second's worth.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Rejects requests before they take the state lock, so a runaway scraper can't
+// keep the exporter busy globbing and reading
+async fn rate_limit_middleware(
+    limiter: Arc<Mutex<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !limiter.lock().unwrap().try_acquire() {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "1")],
+            "Scrape rate limit exceeded\n",
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn custom_header_middleware<B>(mut response: Response<B>) -> Response<B> {
+    response.headers_mut().insert(
+        "X-Powered-By",
+        HeaderValue::from_static("nginx-prometheus-exporter"),
+    );
+    response
+}
+
+/// Binds a listener on `addr`. IPv6 sockets only accept IPv6, so `[::]` can be bound
+/// next to `0.0.0.0` on the same port whatever the kernel's dual-stack default.
+pub fn bind(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+pub fn router(state: Arc<Mutex<MetricsState>>) -> Router {
+    let (tail_buffer, powered_by, scrape_rate_limit, debug_token, metrics_path) = {
+        let state = state.lock().unwrap();
+        (
+            state.settings.tail_buffer,
+            state.settings.powered_by,
+            state.settings.scrape_rate_limit,
+            state.settings.debug_token.clone(),
+            state.settings.metrics_path.clone(),
+        )
+    };
+
+    let mut endpoints = vec![metrics_path.clone(), "/metrics.json".to_string()];
+
+    let mut metrics_route = get({
+        let state = Arc::clone(&state);
+        move |headers: HeaderMap| metrics_handler(state, headers)
+    });
+    let mut metrics_json_route = get({
+        let state = Arc::clone(&state);
+        move || metrics_json_handler(state)
+    });
+
+    // Both read the logs, so they share one bucket
+    if let Some(rate) = scrape_rate_limit {
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(rate)));
+        let json_limiter = Arc::clone(&limiter);
+        metrics_route = metrics_route.layer(middleware::from_fn(move |request, next| {
+            rate_limit_middleware(Arc::clone(&limiter), request, next)
+        }));
+        metrics_json_route = metrics_json_route.layer(middleware::from_fn(move |request, next| {
+            rate_limit_middleware(Arc::clone(&json_limiter), request, next)
+        }));
+    }
+
+    let mut app = Router::new()
+        .route(&metrics_path, metrics_route)
+        .route("/metrics.json", metrics_json_route);
+
+    if let Some(token) = debug_token {
+        let token: Arc<str> = token.into();
+
+        if tail_buffer > 0 {
+            endpoints.push("/tail?file=<name>&lines=<n>".to_string());
+            app = app.route(
+                "/tail",
+                get({
+                    let state = Arc::clone(&state);
+                    let token = Arc::clone(&token);
+                    move |headers: HeaderMap, Query(query): Query<TailQuery>| {
+                        tail_handler(state, token, headers, query)
+                    }
+                }),
+            );
+        }
+
+        endpoints.push("/debug/last-error".to_string());
+        endpoints.push("/debug/cardinality?top=<n>".to_string());
+        endpoints.push("/config".to_string());
+        app = app
+            .route(
+                "/debug/last-error",
+                get({
+                    let state = Arc::clone(&state);
+                    let token = Arc::clone(&token);
+                    move |headers: HeaderMap| last_error_handler(state, token, headers)
+                }),
+            )
+            .route(
+                "/debug/cardinality",
+                get({
+                    let state = Arc::clone(&state);
+                    let token = Arc::clone(&token);
+                    move |headers: HeaderMap, Query(query): Query<CardinalityQuery>| {
+                        cardinality_handler(state, token, headers, query)
+                    }
+                }),
+            )
+            .route(
+                "/config",
+                get({
+                    let state = Arc::clone(&state);
+                    move |headers: HeaderMap| config_handler(state, token, headers)
+                }),
+            );
+    }
+
+    let endpoints = Arc::new(endpoints);
+
+    app = app
+        .route("/", {
+            let endpoints = Arc::clone(&endpoints);
+            get(move || landing_handler(endpoints))
+        })
+        .fallback(move || not_found_handler(endpoints));
+
+    if powered_by {
+        app = app.layer(middleware::map_response(custom_header_middleware));
+    }
+
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{log_line, settings, temp_dir};
+    use crate::state::Settings;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use std::future::IntoFuture;
+    use tower::ServiceExt;
+
+    async fn get(app: Router, uri: &str, token: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::get(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn tail_requires_the_debug_token() {
+        let dir = temp_dir("tail-auth");
+        std::fs::write(dir.join("m.log"), log_line("/a", "200", "0.1") + "\n").unwrap();
+
+        let mut settings = settings(&format!("{}/*.log", dir.display()));
+        settings.tail_buffer = 5;
+        settings.debug_token = Some("secret".to_string());
+
+        let mut state = MetricsState::new(settings);
+        state.update_files_map();
+        state.collect().unwrap();
+        let app = router(Arc::new(Mutex::new(state)));
+
+        let (status, _) = get(app.clone(), "/tail?file=m.log", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = get(app.clone(), "/tail?file=m.log", Some("wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = get(app, "/tail?file=m.log", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""path":"/a""#), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn both_address_families_share_a_port() {
+        let v4 = bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind(SocketAddr::new("::".parse().unwrap(), port)).unwrap();
+        assert!(socket2::SockRef::from(&v6).only_v6().unwrap());
+
+        let app = router(Arc::new(Mutex::new(MetricsState::new(settings("unused")))));
+        for listener in [v4, v6] {
+            tokio::spawn(axum::serve(listener, app.clone()).into_future());
+        }
+
+        for host in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+            let body = crate::push::send_request(&host, "GET", "/metrics", &[], &[])
+                .await
+                .unwrap();
+            assert!(String::from_utf8_lossy(&body).contains("nginx_exporter_build_info"));
+        }
+    }
+
+    fn app(settings: Settings) -> Router {
+        router(Arc::new(Mutex::new(MetricsState::new(settings))))
+    }
+
+    #[tokio::test]
+    async fn pattern_matching_nothing_still_serves_metrics() {
+        let dir = temp_dir("no-match");
+        let (status, body) = get(
+            app(settings(&format!("{}/*.log", dir.display()))),
+            "/metrics",
+            None,
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nginx_exporter_files_watched 0\n"));
+        // The histogram family is announced, without any series
+        assert!(body.contains("# TYPE nginx_http_request_duration_seconds histogram\n"));
+        assert!(!body.contains("nginx_http_request_duration_seconds_bucket"));
+    }
+
+    #[tokio::test]
+    async fn start_time_is_stable_across_scrapes() {
+        let app = app(settings("unused"));
+
+        let start_time = |body: &str| {
+            body.lines()
+                .find_map(|line| line.strip_prefix("nginx_exporter_start_time_seconds "))
+                .map(str::to_string)
+                .unwrap()
+        };
+
+        let (_, first) = get(app.clone(), "/metrics", None).await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let (_, second) = get(app, "/metrics", None).await;
+
+        assert!(first.contains("# TYPE nginx_exporter_start_time_seconds gauge\n"));
+        assert_eq!(start_time(&first), start_time(&second));
+        assert!(start_time(&first).parse::<f64>().unwrap() > 1_600_000_000.0);
+    }
+
+    #[tokio::test]
+    async fn powered_by_header_can_be_disabled() {
+        for powered_by in [true, false] {
+            let mut settings = settings("unused");
+            settings.powered_by = powered_by;
+
+            let response = app(settings)
+                .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.headers().get("x-powered-by").is_some(), powered_by);
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_h2c() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, app(settings("unused"))).into_future());
+
+        // Prior knowledge: the HTTP/2 preface is sent right away, without an upgrade
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get(format!("http://{}/metrics", addr))
+            .body(())
+            .unwrap();
+        let (response, _) = client
+            .ready()
+            .await
+            .unwrap()
+            .send_request(request, true)
+            .unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+
+        let mut body = response.into_body();
+        let mut exposition = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            exposition.extend_from_slice(&chunk);
+        }
+        assert!(String::from_utf8_lossy(&exposition).contains("nginx_exporter_build_info"));
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_list_the_endpoints() {
+        let (status, body) = get(app(settings("unused")), "/metric", None).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(
+            body.starts_with("Not found. Available endpoints:\n"),
+            "{}",
+            body
+        );
+        assert!(body.contains("  /metrics\n"));
+        assert!(body.contains("  /metrics.json\n"));
+        // Debug endpoints are only listed when they are served
+        assert!(!body.contains("/config"));
+    }
+
+    #[tokio::test]
+    async fn landing_page_links_the_metrics() {
+        let (status, body) = get(app(settings("unused")), "/", None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(&format!("Version {}", env!("CARGO_PKG_VERSION"))));
+        assert!(
+            body.contains(r#"<a href="/metrics">/metrics</a>"#),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn bursts_above_the_scrape_rate_limit_get_429() {
+        let mut settings = settings("unused");
+        settings.scrape_rate_limit = Some(2.0);
+        let app = app(settings);
+
+        let mut statuses = Vec::new();
+        for uri in ["/metrics", "/metrics.json", "/metrics", "/metrics"] {
+            statuses.push(get(app.clone(), uri, None).await.0);
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+
+        // Other endpoints don't read the logs and aren't limited
+        assert_eq!(get(app, "/", None).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn last_error_returns_the_latest_bad_line() {
+        let mut settings = settings("unused");
+        settings.debug_token = Some("secret".to_string());
+        let state = Arc::new(Mutex::new(MetricsState::new(settings)));
+        let app = router(Arc::clone(&state));
+
+        let (status, body) = get(app.clone(), "/debug/last-error", Some("secret")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "null"));
+
+        {
+            let mut state = state.lock().unwrap();
+            let mut recent = std::collections::VecDeque::new();
+            for line in ["{\"http\":", "{\"nginx\": 1}"] {
+                state.record_line(line, &mut recent).unwrap();
+            }
+        }
+
+        let (status, _) = get(app.clone(), "/debug/last-error", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = get(app, "/debug/last-error", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["line"], "{\"nginx\": 1}");
+        assert!(
+            error["error"].as_str().unwrap().contains("invalid type"),
+            "{}",
+            body
+        );
+        assert!(error["timestamp"].as_f64().unwrap() > 1_600_000_000.0);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_at_the_configured_path() {
+        let mut settings = settings("unused");
+        settings.metrics_path = "/nginx/metrics".to_string();
+        let app = app(settings);
+
+        let (status, body) = get(app.clone(), "/nginx/metrics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nginx_exporter_build_info"));
+
+        let (status, body) = get(app, "/metrics", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("  /nginx/metrics\n"));
+    }
+
+    #[tokio::test]
+    async fn read_errors_answer_with_a_json_body() {
+        let response = read_failed("Failed to read line: Input/output error".to_string());
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"], "read_failed");
+        assert_eq!(error["message"], "Failed to read line: Input/output error");
+    }
+
+    #[tokio::test]
+    async fn metrics_json_has_one_object_per_series() {
+        let dir = temp_dir("metrics-json");
+        std::fs::write(
+            dir.join("access.log"),
+            [log_line("/a", "200", "0.05"), log_line("/a", "200", "0.5")].join("\n") + "\n",
+        )
+        .unwrap();
+
+        let mut settings = settings(&format!("{}/*.log", dir.display()));
+        settings.buckets = vec![0.1, 1.0];
+        let (status, body) = get(app(settings), "/metrics.json", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "name": "nginx_http_request_duration_seconds",
+                "labels": {
+                    "method": "GET",
+                    "path": "/a",
+                    "status_code": "2xx",
+                    "host": "example.com",
+                },
+                "buckets": [
+                    {"le": "0.1", "count": 1},
+                    {"le": "1", "count": 2},
+                    {"le": "+Inf", "count": 2},
+                ],
+                "sum": 0.55,
+                "count": 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn cardinality_reports_the_top_values_per_label() {
+        let mut settings = settings("unused");
+        settings.debug_token = Some("secret".to_string());
+        let mut state = MetricsState::new(settings);
+        crate::state::tests::record(
+            &mut state,
+            &[
+                log_line("/hot", "200", "0.1"),
+                log_line("/hot", "404", "0.1"),
+                log_line("/hot", "500", "0.1"),
+                log_line("/warm", "200", "0.1"),
+                log_line("/warm", "404", "0.1"),
+                log_line("/cold", "200", "0.1"),
+            ],
+        );
+        let app = router(Arc::new(Mutex::new(state)));
+
+        let (status, _) = get(app.clone(), "/debug/cardinality", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = get(app, "/debug/cardinality?top=2", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        let cardinality: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            cardinality["path"],
+            serde_json::json!({
+                "distinct": 3,
+                "top": [
+                    {"value": "/hot", "series": 3},
+                    {"value": "/warm", "series": 2},
+                ],
+            })
+        );
+        assert_eq!(cardinality["host"]["distinct"], 1);
+    }
+
+    #[tokio::test]
+    async fn metrics_content_type_is_version_tagged() {
+        let scrape = |settings: Settings, accept: &'static str| async move {
+            let response = app(settings)
+                .oneshot(
+                    Request::get("/metrics")
+                        .header(header::ACCEPT, accept)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            response.headers()[header::CONTENT_TYPE].clone()
+        };
+
+        assert_eq!(
+            scrape(settings("unused"), "*/*").await,
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+
+        // OpenMetrics is only negotiated along with exemplars
+        let mut exemplars = settings("unused");
+        exemplars.with_exemplars = true;
+        assert_eq!(
+            scrape(exemplars, "application/openmetrics-text; version=1.0.0").await,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_are_unavailable_during_the_warmup() {
+        let mut settings = settings("unused");
+        settings.warmup = Some(std::time::Duration::from_secs(15));
+        let state = Arc::new(Mutex::new(MetricsState::new(settings)));
+        let app = router(Arc::clone(&state));
+
+        let (status, body) = get(app.clone(), "/metrics", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("warming_up"));
+
+        // As if the exporter had started 20 seconds ago
+        state.lock().unwrap().start_time -= 20.0;
+        let (status, body) = get(app, "/metrics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nginx_exporter_build_info"));
+    }
+
+    #[tokio::test]
+    async fn expositions_end_with_a_single_newline() {
+        let (_, text) = get(app(settings("unused")), "/metrics", None).await;
+        assert!(text.ends_with('\n') && !text.ends_with("\n\n"));
+
+        let mut exemplars = settings("unused");
+        exemplars.with_exemplars = true;
+        let response = app(exemplars)
+            .oneshot(
+                Request::get("/metrics")
+                    .header(header::ACCEPT, "application/openmetrics-text")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let openmetrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(openmetrics.ends_with("\n# EOF\n"), "{}", openmetrics);
+        assert!(!openmetrics.contains("\n\n"));
+    }
+
+    #[tokio::test]
+    async fn a_vanished_file_still_serves_metrics() {
+        let dir = temp_dir("vanished");
+        let path = dir.join("access.log");
+        std::fs::write(&path, log_line("/a", "200", "0.1") + "\n").unwrap();
+
+        let mut state = MetricsState::new(settings(&format!("{}/*.log", dir.display())));
+        state.update_files_map();
+        state.read_new_entries().unwrap();
+
+        // Gone between two read cycles, while still in the watch set
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+        state.read_new_entries().unwrap();
+
+        let app = router(Arc::new(Mutex::new(state)));
+        let (status, body) = get(app, "/metrics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nginx_http_request_duration_seconds_count"));
+    }
+}
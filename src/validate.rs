@@ -0,0 +1,231 @@
+use crate::metrics::Observations;
+use crate::parser::trim_line;
+use crate::state::{MetricsState, Settings};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Fields read from a log entry besides the status, with the JSON pointers they may
+/// be found at. Together with the status, the first three are required for an entry
+/// to be counted.
+const FIELDS: &[(&str, &[&str])] = &[
+    ("nginx.access.method", &["/nginx/access/method"]),
+    ("nginx.access.url", &["/nginx/access/url"]),
+    ("nginx.access.host", &["/nginx/access/host"]),
+    ("nginx.time.request", &["/nginx/time/request"]),
+    (
+        "nginx.access.http_protocol",
+        &[
+            "/nginx/access/http_protocol",
+            "/nginx/access/server_protocol",
+        ],
+    ),
+    (
+        "nginx.access.scheme",
+        &["/nginx/access/scheme", "/nginx/access/server_port"],
+    ),
+    (
+        "nginx.http.http_x_b3_traceid",
+        &["/nginx/http/http_x_b3_traceid", "/nginx/http/trace_id"],
+    ),
+    (
+        "nginx.bytes.request_length",
+        &["/nginx/bytes/request_length"],
+    ),
+    ("nginx.pid", &["/nginx/pid"]),
+    (
+        "http.response.cache_control",
+        &["/http/response/cache_control"],
+    ),
+    ("nginx.upstream.status", &["/nginx/upstream/status"]),
+];
+
+const REQUIRED_FIELDS: usize = 4;
+
+const EXAMPLE_LABELS: usize = 3;
+
+/// Summary of running the first lines of a sample log through the parser.
+pub struct Report {
+    pub lines: usize,
+    pub objects: usize,
+    pub recorded: usize,
+    // Counted, but left out of the duration histogram
+    pub missing_durations: u64,
+    pub syntax_errors: u64,
+    pub structural_errors: u64,
+    pub field_counts: Vec<(String, usize)>,
+    pub examples: Vec<String>,
+}
+
+impl Report {
+    /// A sample is usable when at least one entry made it into the histograms.
+    pub fn is_usable(&self) -> bool {
+        self.recorded > 0
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Lines read:        {}", self.lines)?;
+        writeln!(f, "Entries recorded:  {}", self.recorded)?;
+        writeln!(f, "Without duration:  {}", self.missing_durations)?;
+        writeln!(f, "Syntax errors:     {}", self.syntax_errors)?;
+        writeln!(f, "Structural errors: {}", self.structural_errors)?;
+
+        writeln!(f, "\nField coverage ({} JSON objects):", self.objects)?;
+        for (i, (field, count)) in self.field_counts.iter().enumerate() {
+            let note = match (count, i < REQUIRED_FIELDS) {
+                (0, true) => "  MISSING (required)",
+                (0, false) => "  missing",
+                _ => "",
+            };
+            writeln!(f, "  {:<30} {:>6}{}", field, count, note)?;
+        }
+
+        writeln!(f, "\nExample labels:")?;
+        if self.examples.is_empty() {
+            writeln!(f, "  none")?;
+        }
+        for example in &self.examples {
+            writeln!(f, "  {}", example)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn count_fields(
+    value: &Value,
+    fields: &[(String, Vec<String>)],
+    counts: &mut [(String, usize)],
+) -> usize {
+    match value {
+        Value::Array(values) => values.iter().map(|v| count_fields(v, fields, counts)).sum(),
+        Value::Object(_) => {
+            for ((_, pointers), (_, count)) in fields.iter().zip(counts.iter_mut()) {
+                if pointers.iter().any(|p| value.pointer(p).is_some()) {
+                    *count += 1;
+                }
+            }
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// Runs the first `max_lines` lines of `path` through the same parsing as the
+/// exporter, without watching any files.
+pub fn validate_sample(path: &str, max_lines: usize, settings: Settings) -> Result<Report, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+
+    // The status is found under any of the configured names
+    let status_field = (
+        format!("http.response.{}", settings.status_fields.join("|")),
+        settings
+            .status_fields
+            .iter()
+            .map(|name| format!("/http/response/{}", name))
+            .collect(),
+    );
+    let fields: Vec<(String, Vec<String>)> = std::iter::once(status_field)
+        .chain(FIELDS.iter().map(|(field, pointers)| {
+            (
+                field.to_string(),
+                pointers.iter().map(|p| p.to_string()).collect(),
+            )
+        }))
+        .collect();
+
+    let mut state = MetricsState::new(settings);
+    let mut recent = VecDeque::new();
+
+    let mut lines = 0;
+    let mut objects = 0;
+    let mut field_counts: Vec<_> = fields.iter().map(|(field, _)| (field.clone(), 0)).collect();
+
+    for line in BufReader::new(file).lines().take(max_lines) {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        lines += 1;
+
+        if let Ok(value) = serde_json::from_str::<Value>(trim_line(&line)) {
+            objects += count_fields(&value, &fields, &mut field_counts);
+        }
+
+        state.record_line(&line, &mut recent)?;
+    }
+
+    let mut labels: Vec<_> = state.metrics.keys().collect();
+    labels.sort();
+
+    let examples = labels
+        .into_iter()
+        .take(EXAMPLE_LABELS)
+        .map(|labels| {
+            let pairs: Vec<_> = labels
+                .pairs()
+                .into_iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, value))
+                .collect();
+            format!("{{{}}}", pairs.join(","))
+        })
+        .collect();
+
+    Ok(Report {
+        lines,
+        objects,
+        recorded: state.metrics.values().map(Observations::count).sum(),
+        missing_durations: state.missing_duration_total,
+        syntax_errors: state.syntax_errors_total,
+        structural_errors: state.structural_errors_total,
+        field_counts,
+        examples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{log_line, settings, temp_dir};
+
+    #[test]
+    fn report_counts_fields_and_errors() {
+        let path = temp_dir("validate").join("sample.log");
+        let lines = [
+            log_line("/a", "200", "0.1"),
+            log_line("/b", "404", ""),
+            r#"{"http":{"response":{"status_code":"200"}}}"#.to_string(),
+            "not json".to_string(),
+            log_line("/c", "200", "0.3"),
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let report = validate_sample(&path.to_string_lossy(), 4, settings("unused")).unwrap();
+
+        assert_eq!(report.lines, 4);
+        assert_eq!(report.objects, 3);
+        assert_eq!(report.recorded, 1);
+        assert_eq!(report.missing_durations, 1);
+        assert_eq!(report.syntax_errors, 1);
+        assert_eq!(report.structural_errors, 1);
+        assert!(report.is_usable());
+
+        let count = |field: &str| {
+            report
+                .field_counts
+                .iter()
+                .find(|(name, _)| name == field)
+                .map(|&(_, count)| count)
+        };
+        assert_eq!(count("nginx.access.method"), Some(2));
+        assert_eq!(count("nginx.time.request"), Some(2));
+        assert_eq!(count("nginx.pid"), Some(0));
+        assert_eq!(report.examples.len(), 1);
+        assert!(report.examples[0].contains(r#"path="/a""#));
+
+        let text = report.to_string();
+        assert!(text.contains("Entries recorded:  1\n"), "{}", text);
+        assert!(text.contains("nginx.pid"));
+        assert!(text.contains("  missing\n"));
+    }
+}